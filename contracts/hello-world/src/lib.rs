@@ -1,20 +1,26 @@
 #![no_std]
 
 use soroban_sdk::{
-    contract, contractimpl, contracttype, symbol_short, IntoVal,
+    contract, contractclient, contractimpl, contracttype, symbol_short, token, IntoVal,
     Address, Bytes, BytesN, Env, Error as SdkError, Symbol, Vec, Val,
 };
 
+mod test;
+
 // Constants - shortened to max 9 characters
 const ADMIN: Symbol = symbol_short!("admin");
 const WAL_WASM: Symbol = symbol_short!("walwasm");
 const EVENT_TAG: Symbol = symbol_short!("USRMGR");
+const BASE_DECIMALS: u32 = 7; // Decimals of base currency prices and values
 
 // Error codes
 const ERROR_ALREADY_REGISTERED: u32 = 1;
 const ERROR_NOT_FOUND: u32 = 2;
 const ERROR_UNAUTHORIZED: u32 = 3;
 const ERROR_INVALID_WASM: u32 = 4;
+const ERROR_POLICY_NOT_APPROVED: u32 = 5;
+const ERROR_PRICE_UNAVAILABLE: u32 = 6;
+const ERROR_VALUE_OVERFLOW: u32 = 7;
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -34,12 +40,44 @@ pub enum WalletType {
     Custom,
 }
 
+// SEP-40 price feed types
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceData {
+    pub price: i128,
+    pub timestamp: u64,
+}
+
+#[contractclient(name = "PriceOracleClient")]
+pub trait PriceOracle {
+    fn decimals(env: Env) -> u32;
+    fn lastprice(env: Env, asset: Asset) -> Option<PriceData>;
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OracleConfig {
+    pub oracle: Address,
+    pub max_age: u64, // Seconds after which an oracle price is stale and the fallback is used
+}
+
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum DataKey {
     Users(Bytes),         // Map of user_id -> User
     UserByWallet(Address), // Map of wallet_address -> user_id
     WalletTypes(Address),  // Map of wallet_address -> WalletType
+    PolicyWasm(BytesN<32>), // Set of policy WASM hashes vetted by the admin
+    Policy(Address),        // Map of deployed policy address -> WASM hash
+    Oracle,                 // OracleConfig for valuing wallet spending in the base currency
+    FallbackPrice(Address), // Map of token -> admin set base currency price
 }
 
 #[contract]
@@ -289,6 +327,207 @@ impl UserManager {
         Ok(())
     }
 
+    /// Approve a policy WASM so wallets can attach instances of it (admin only)
+    pub fn approve_policy_wasm(env: Env, wasm_hash: BytesN<32>) -> Result<(), SdkError> {
+        // Verify admin
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+
+        env.storage().instance().set(&DataKey::PolicyWasm(wasm_hash.clone()), &true);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pol_ok")), wasm_hash);
+
+        Ok(())
+    }
+
+    /// Revoke a policy WASM, every instance of it stops being approved (admin only)
+    pub fn revoke_policy_wasm(env: Env, wasm_hash: BytesN<32>) -> Result<(), SdkError> {
+        // Verify admin
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+
+        let key = DataKey::PolicyWasm(wasm_hash.clone());
+
+        if !env.storage().instance().has(&key) {
+            return Err(SdkError::from_contract_error(ERROR_NOT_FOUND));
+        }
+
+        env.storage().instance().remove(&key);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pol_revk")), wasm_hash);
+
+        Ok(())
+    }
+
+    /// Deploy an instance of an approved policy WASM, e.g. a merchant allowlist for one wallet
+    pub fn deploy_policy(
+        env: Env,
+        wasm_hash: BytesN<32>,
+        salt: BytesN<32>,
+        constructor_args: Vec<Val>,
+    ) -> Result<Address, SdkError> {
+        if !Self::is_policy_wasm_approved(env.clone(), wasm_hash.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_POLICY_NOT_APPROVED));
+        }
+
+        let policy_address = env
+            .deployer()
+            .with_current_contract(salt)
+            .deploy_v2(wasm_hash.clone(), constructor_args);
+
+        // Remember which WASM the instance runs so wallets can check it later
+        env.storage().instance().set(&DataKey::Policy(policy_address.clone()), &wasm_hash);
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("newpol")),
+            (policy_address.clone(), wasm_hash)
+        );
+
+        Ok(policy_address)
+    }
+
+    /// Check if a policy WASM is approved
+    pub fn is_policy_wasm_approved(env: Env, wasm_hash: BytesN<32>) -> bool {
+        env.storage().instance().has(&DataKey::PolicyWasm(wasm_hash))
+    }
+
+    /// Check if a policy contract was deployed from a WASM that is still approved
+    pub fn is_policy_approved(env: Env, policy: Address) -> bool {
+        let wasm_hash: Option<BytesN<32>> = env
+            .storage()
+            .instance()
+            .get(&DataKey::Policy(policy));
+
+        match wasm_hash {
+            Some(wasm_hash) => Self::is_policy_wasm_approved(env, wasm_hash),
+            None => false,
+        }
+    }
+
+    /// Set the SEP-40 oracle wallets value their spending with (admin only)
+    pub fn set_oracle(env: Env, oracle: Address, max_age: u64) -> Result<(), SdkError> {
+        // Verify admin
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+
+        let config = OracleConfig { oracle, max_age };
+        env.storage().instance().set(&DataKey::Oracle, &config);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("oracle")), config);
+
+        Ok(())
+    }
+
+    /// Get the oracle configuration
+    pub fn get_oracle(env: Env) -> Result<OracleConfig, SdkError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Oracle)
+            .ok_or(SdkError::from_contract_error(ERROR_NOT_FOUND))
+    }
+
+    /// Set the price of one whole token in the base currency (7 decimals), used when the
+    /// oracle has no fresh price for it (admin only)
+    pub fn set_fallback_price(env: Env, token: Address, price: i128) -> Result<(), SdkError> {
+        // Verify admin
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+
+        if price <= 0 {
+            return Err(SdkError::from_contract_error(ERROR_PRICE_UNAVAILABLE));
+        }
+
+        env.storage().instance().set(&DataKey::FallbackPrice(token.clone()), &price);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("fallback")), (token, price));
+
+        Ok(())
+    }
+
+    /// Remove a fallback price (admin only)
+    pub fn remove_fallback_price(env: Env, token: Address) -> Result<(), SdkError> {
+        // Verify admin
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+
+        let key = DataKey::FallbackPrice(token.clone());
+
+        if !env.storage().instance().has(&key) {
+            return Err(SdkError::from_contract_error(ERROR_NOT_FOUND));
+        }
+
+        env.storage().instance().remove(&key);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("fallback")), (token, 0i128));
+
+        Ok(())
+    }
+
+    /// Get the price of one whole token in the base currency (7 decimals). A fresh oracle
+    /// price wins, a missing or stale one or a failing oracle falls back to the admin set price.
+    pub fn get_price(env: Env, token: Address) -> Result<i128, SdkError> {
+        let config: Option<OracleConfig> = env.storage().instance().get(&DataKey::Oracle);
+
+        if let Some(config) = config {
+            if let Some(price) = Self::oracle_price(&env, &config, &token) {
+                return Ok(price);
+            }
+        }
+
+        env.storage()
+            .instance()
+            .get(&DataKey::FallbackPrice(token))
+            .ok_or(SdkError::from_contract_error(ERROR_PRICE_UNAVAILABLE))
+    }
+
+    /// Value of a token amount in the base currency (7 decimals)
+    pub fn value_of(env: Env, token: Address, amount: i128) -> Result<i128, SdkError> {
+        let price = Self::get_price(env.clone(), token.clone())?;
+        let token_decimals = token::Client::new(&env, &token).decimals();
+
+        let value = amount
+            .checked_mul(price)
+            .ok_or(SdkError::from_contract_error(ERROR_VALUE_OVERFLOW))?;
+
+        Self::rescale(value, token_decimals, 0)
+    }
+
+    // Fresh oracle price, None if the oracle has none or fails, so a broken oracle can't block
+    // every wallet spending through it
+    fn oracle_price(env: &Env, config: &OracleConfig, token: &Address) -> Option<i128> {
+        let oracle = PriceOracleClient::new(env, &config.oracle);
+        let data = oracle.try_lastprice(&Asset::Stellar(token.clone())).ok()?.ok()??;
+
+        let now = env.ledger().timestamp();
+        let fresh = data.timestamp <= now && now - data.timestamp <= config.max_age;
+
+        if data.price <= 0 || !fresh {
+            return None;
+        }
+
+        let decimals = oracle.try_decimals().ok()?.ok()?;
+        Self::rescale(data.price, decimals, BASE_DECIMALS).ok()
+    }
+
+    // Move a fixed point value from one number of decimals to another, rounding down
+    fn rescale(value: i128, from_decimals: u32, to_decimals: u32) -> Result<i128, SdkError> {
+        let overflow = SdkError::from_contract_error(ERROR_VALUE_OVERFLOW);
+
+        if from_decimals >= to_decimals {
+            let divisor = 10i128.checked_pow(from_decimals - to_decimals).ok_or(overflow)?;
+            Ok(value / divisor)
+        } else {
+            let factor = 10i128.checked_pow(to_decimals - from_decimals).ok_or(overflow)?;
+            value.checked_mul(factor).ok_or(overflow)
+        }
+    }
+
     /// Get all wallets for a user
     pub fn get_user_wallets(env: Env, user_id: Bytes) -> Result<Vec<Address>, SdkError> {
         let user: User = env
@@ -1,21 +1,125 @@
 #![cfg(test)]
 
 use super::*;
-use soroban_sdk::{vec, Env, String};
+use soroban_sdk::testutils::{ Address as _, Ledger };
+
+// SEP-40 oracle returning whatever prices the test sets, or failing every call once broken
+#[contract]
+pub struct MockOracle;
+
+#[contractimpl]
+impl MockOracle {
+    pub fn set_price(env: Env, asset: Asset, price: i128, timestamp: u64) {
+        env.storage().instance().set(&asset, &PriceData { price, timestamp });
+    }
+
+    pub fn set_broken(env: Env) {
+        env.storage().instance().set(&symbol_short!("broken"), &true);
+    }
+
+    pub fn decimals(env: Env) -> u32 {
+        Self::check_up(&env);
+        14
+    }
+
+    pub fn lastprice(env: Env, asset: Asset) -> Option<PriceData> {
+        Self::check_up(&env);
+        env.storage().instance().get(&asset)
+    }
+
+    fn check_up(env: &Env) {
+        if env.storage().instance().has(&symbol_short!("broken")) {
+            panic!("oracle down");
+        }
+    }
+}
+
+const NOW: u64 = 1_700_000_000;
+const MAX_AGE: u64 = 5 * 60;
+
+fn setup(env: &Env) -> (UserManagerClient<'_>, MockOracleClient<'_>, Address) {
+    env.mock_all_auths();
+    env.ledger().set_timestamp(NOW);
+
+    let admin = Address::generate(env);
+    let manager_id = env.register(UserManager, (admin.clone(), BytesN::from_array(env, &[0; 32])));
+    let manager = UserManagerClient::new(env, &manager_id);
+
+    let oracle = MockOracleClient::new(env, &env.register(MockOracle, ()));
+    manager.set_oracle(&oracle.address, &MAX_AGE);
+
+    // Stellar asset contracts have 7 decimals
+    let token = env.register_stellar_asset_contract_v2(admin).address();
+
+    (manager, oracle, token)
+}
 
 #[test]
-fn test() {
+fn values_amounts_with_the_oracle_price() {
     let env = Env::default();
-    let contract_id = env.register(Contract, ());
-    let client = ContractClient::new(&env, &contract_id);
+    let (manager, oracle, token) = setup(&env);
+
+    // $0.12 with the oracle's 14 decimals
+    oracle.set_price(&Asset::Stellar(token.clone()), &12_000_000_000_000, &(NOW - 60));
+
+    assert_eq!(manager.get_price(&token), 1_200_000);
+    assert_eq!(manager.value_of(&token, &100_0000000), 12_0000000);
+}
+
+#[test]
+fn oracle_price_wins_over_fallback() {
+    let env = Env::default();
+    let (manager, oracle, token) = setup(&env);
+
+    oracle.set_price(&Asset::Stellar(token.clone()), &12_000_000_000_000, &NOW);
+    manager.set_fallback_price(&token, &1_0000000);
+
+    assert_eq!(manager.value_of(&token, &100_0000000), 12_0000000);
+}
+
+#[test]
+fn stale_oracle_price_uses_fallback() {
+    let env = Env::default();
+    let (manager, oracle, token) = setup(&env);
+
+    oracle.set_price(&Asset::Stellar(token.clone()), &12_000_000_000_000, &(NOW - MAX_AGE - 1));
+    manager.set_fallback_price(&token, &1_0000000);
+
+    assert_eq!(manager.value_of(&token, &100_0000000), 100_0000000);
+}
+
+#[test]
+fn missing_oracle_price_uses_fallback() {
+    let env = Env::default();
+    let (manager, _, token) = setup(&env);
+
+    manager.set_fallback_price(&token, &5000000);
+
+    assert_eq!(manager.value_of(&token, &3_0000000), 1_5000000);
+}
+
+#[test]
+fn failing_oracle_uses_fallback() {
+    let env = Env::default();
+    let (manager, oracle, token) = setup(&env);
+
+    oracle.set_price(&Asset::Stellar(token.clone()), &12_000_000_000_000, &NOW);
+    oracle.set_broken();
+    manager.set_fallback_price(&token, &5000000);
+
+    assert_eq!(manager.value_of(&token, &3_0000000), 1_5000000);
+}
+
+#[test]
+fn no_price_is_an_error() {
+    let env = Env::default();
+    let (manager, oracle, token) = setup(&env);
+
+    // Stale and without a fallback
+    oracle.set_price(&Asset::Stellar(token.clone()), &12_000_000_000_000, &(NOW - MAX_AGE - 1));
 
-    let words = client.hello(&String::from_str(&env, "Dev"));
     assert_eq!(
-        words,
-        vec![
-            &env,
-            String::from_str(&env, "Hello"),
-            String::from_str(&env, "Dev"),
-        ]
+        manager.try_value_of(&token, &100_0000000),
+        Err(Ok(SdkError::from_contract_error(ERROR_PRICE_UNAVAILABLE)))
     );
 }
@@ -2,9 +2,11 @@
 #![no_std]
 
 use soroban_sdk::{
+    auth::Context,
     contract,
     contractimpl,
     contracttype,
+    crypto::Hash,
     symbol_short,
     token::{ self },
     Address,
@@ -12,17 +14,23 @@ use soroban_sdk::{
     BytesN,
     Env,
     Error as SdkError,
+    IntoVal,
     Symbol,
+    TryFromVal,
     Vec,
 };
 
 mod base64_urls;
+// Shared with NBSWallet (uwallet) so both wallets read clientDataJSON the same way
+#[path = "../../../uwallet/src/client_data.rs"]
+mod client_data;
+mod test;
 
 // Constants
 const WEEK_OF_LEDGERS: u32 = ((60 * 60 * 24) / 5) * 7;
 const EVENT_TAG: Symbol = symbol_short!("SMWALLET");
 const MAX_DAILY_LIMIT: i128 = 10_000_0000000; // $10,000 with 7 decimals
-const RECOVERY_DELAY: u64 = ((60 * 60 * 24) / 5) * 7; // 1 week in ledgers
+const MAX_CLIENT_DATA_LEN: u32 = 1024;
 
 // Error codes
 const ERROR_ALREADY_INITIALIZED: u32 = 1;
@@ -32,9 +40,12 @@ const ERROR_INSUFFICIENT_BALANCE: u32 = 4;
 const ERROR_DAILY_LIMIT_EXCEEDED: u32 = 5;
 const ERROR_UNAUTHORIZED: u32 = 6;
 const ERROR_INVALID_SIGNATURE: u32 = 7;
-const ERROR_RECOVERY_PENDING: u32 = 8;
-const ERROR_NO_RECOVERY_PENDING: u32 = 9;
 const ERROR_TOKEN_NOT_ALLOWED: u32 = 10;
+const ERROR_SIGNER_NOT_FOUND: u32 = 11;
+const ERROR_SIGNER_EXISTS: u32 = 12;
+const ERROR_INVALID_SIGNER: u32 = 13;
+const ERROR_CHALLENGE_MISMATCH: u32 = 14;
+const ERROR_INVALID_CLIENT_DATA: u32 = 15;
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -52,6 +63,29 @@ pub struct WebAuthnSignature {
     pub signature: BytesN<64>,
 }
 
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ed25519Signature {
+    pub public_key: BytesN<32>,
+    pub signature: BytesN<64>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Signer {
+    Passkey(Bytes),      // Credential id of the wallet passkey
+    Ed25519(BytesN<32>), // Raw Ed25519 public key, e.g. a backend hot key
+    Account(Address),    // Any Stellar account or contract, e.g. the owner
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SignerSignature {
+    Passkey(WebAuthnSignature),
+    Ed25519(Ed25519Signature),
+    Account(Address), // The account authorizes the signature payload through its own auth
+}
+
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DailySpending {
@@ -70,21 +104,6 @@ pub struct Transaction {
     pub tx_hash: Bytes,
 }
 
-#[contracttype]
-pub struct Signature {
-    pub authenticator_data: Bytes,
-    pub client_data_json: Bytes,
-    pub signature: BytesN<64>,
-}
-
-#[contracttype]
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct RecoveryRequest {
-    pub new_passkey: PasskeyCredential,
-    pub requested_at: u64,
-    pub expires_at: u64,
-}
-
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum WalletType {
@@ -99,12 +118,11 @@ pub enum WalletType {
 pub enum DataKey {
     Passkey,
     DailySpending,
-    Recovery,
     TransactionHistory,
     Settings,
     AllowedTokens,
     WalletType,
-    Owner,
+    Signers, // Ed25519 and account signers next to the passkey
 }
 
 #[contracttype]
@@ -153,7 +171,11 @@ impl NBSWallet {
         env.storage().instance().set(&DataKey::Passkey, &passkey);
         env.storage().instance().set(&DataKey::Settings, &settings);
         env.storage().instance().set(&DataKey::WalletType, &wallet_type);
-        env.storage().instance().set(&DataKey::Owner, &owner);
+
+        // The owner authorizes through its own account as a signer next to the passkey
+        let mut signers = Vec::<Signer>::new(&env);
+        signers.push_back(Signer::Account(owner));
+        env.storage().instance().set(&DataKey::Signers, &signers);
 
         // Store allowed tokens if provided for custom wallet type
         if wallet_type == WalletType::Custom && allowed_tokens.is_some() {
@@ -340,9 +362,123 @@ impl NBSWallet {
         token::Client::new(&env, &token).balance(&wallet_address)
     }
 
-    /// Get wallet owner
-    pub fn get_owner(env: Env) -> Address {
-        env.storage().instance().get(&DataKey::Owner).unwrap()
+    /// Get wallet owner, the first account signer
+    pub fn get_owner(env: Env) -> Result<Address, SdkError> {
+        Self::get_signers(&env)?
+            .iter()
+            .find_map(|signer| match signer {
+                Signer::Account(owner) => Some(owner),
+                _ => None,
+            })
+            .ok_or(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND))
+    }
+
+    /// Add an Ed25519 key or a Stellar account as a signer
+    pub fn add_signer(env: Env, signer: Signer) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        // The wallet has exactly one passkey, set at initialize
+        if let Signer::Passkey(_) = signer {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNER));
+        }
+
+        let mut signers = Self::get_signers(&env)?;
+
+        if signers.contains(&signer) {
+            return Err(SdkError::from_contract_error(ERROR_SIGNER_EXISTS));
+        }
+
+        signers.push_back(signer.clone());
+        env.storage().instance().set(&DataKey::Signers, &signers);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("sig_add")), signer);
+
+        Ok(())
+    }
+
+    /// Remove an Ed25519 key or a Stellar account signer, the passkey always stays
+    pub fn remove_signer(env: Env, signer: Signer) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if let Signer::Passkey(_) = signer {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNER));
+        }
+
+        let mut signers = Self::get_signers(&env)?;
+        let index = signers
+            .first_index_of(&signer)
+            .ok_or(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND))?;
+
+        signers.remove(index);
+        env.storage().instance().set(&DataKey::Signers, &signers);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("sig_rm")), signer);
+
+        Ok(())
+    }
+
+    /// Get every signer of the wallet, the passkey first
+    pub fn list_signers(env: Env) -> Result<Vec<Signer>, SdkError> {
+        let passkey = Self::get_passkey(&env)?;
+        let mut signers = Vec::from_array(&env, [Signer::Passkey(passkey.id)]);
+        signers.append(&Self::get_signers(&env)?);
+
+        Ok(signers)
+    }
+
+    /// Signature verification for the passkey, Ed25519 and Stellar account signers
+    pub fn __check_auth(
+        env: Env,
+        signature_payload: Hash<32>,
+        signature: SignerSignature,
+        auth_contexts: Vec<Context>
+    ) -> Result<(), SdkError> {
+        let signers = Self::get_signers(&env)?;
+
+        match signature {
+            SignerSignature::Passkey(signature) => {
+                Self::verify_passkey(&env, &signature_payload, signature)?;
+            }
+            SignerSignature::Ed25519(signature) => {
+                let signer = Signer::Ed25519(signature.public_key.clone());
+
+                if !signers.contains(&signer) {
+                    return Err(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND));
+                }
+
+                env.crypto().ed25519_verify(
+                    &signature.public_key,
+                    &Bytes::from_array(&env, &signature_payload.to_array()),
+                    &signature.signature
+                );
+            }
+            SignerSignature::Account(address) => {
+                let signer = Signer::Account(address.clone());
+
+                if !signers.contains(&signer) {
+                    return Err(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND));
+                }
+
+                // Delegate to the account's own auth, scoped to this exact payload
+                let payload = BytesN::from_array(&env, &signature_payload.to_array());
+                address.require_auth_for_args((payload,).into_val(&env));
+            }
+        }
+
+        // Direct token calls never reach send/withdraw, so they are held to the same rules here
+        Self::check_token_calls(&env, &auth_contexts)?;
+
+        // Extend TTL on successful auth
+        let max_ttl = env.storage().max_ttl();
+        env.storage()
+            .instance()
+            .extend_ttl(max_ttl - WEEK_OF_LEDGERS, max_ttl);
+
+        Ok(())
     }
 
     /// Get wallet type
@@ -391,6 +527,128 @@ impl NBSWallet {
     }
 
     // Helper functions
+    fn get_passkey(env: &Env) -> Result<PasskeyCredential, SdkError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Passkey)
+            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))
+    }
+
+    /// WebAuthn assertion of the wallet passkey over the signature payload
+    fn verify_passkey(
+        env: &Env,
+        signature_payload: &Hash<32>,
+        signature: WebAuthnSignature
+    ) -> Result<(), SdkError> {
+        let passkey = Self::get_passkey(env)?;
+
+        // The assertion must be a webauthn.get whose challenge is the base64url (unpadded)
+        // encoded signature payload
+        if signature.client_data_json.len() > MAX_CLIENT_DATA_LEN {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA));
+        }
+
+        let client_data_json = signature.client_data_json
+            .to_buffer::<{ MAX_CLIENT_DATA_LEN as usize }>();
+        let client_data = client_data::parse(client_data_json.as_slice())
+            .map_err(|_| SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA))?;
+
+        if client_data.type_ != b"webauthn.get" {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA));
+        }
+
+        let mut expected_challenge = [0u8; 43];
+        base64_urls::encode(&mut expected_challenge, &signature_payload.to_array());
+
+        if client_data.challenge != expected_challenge {
+            return Err(SdkError::from_contract_error(ERROR_CHALLENGE_MISMATCH));
+        }
+
+        // Check user presence flag (bit 0 of the flags byte)
+        if signature.authenticator_data.len() < 37 {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        }
+
+        if (signature.authenticator_data.get(32).unwrap_or(0) & 0x01) == 0 {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        }
+
+        // The passkey signs authenticator_data || sha256(client_data_json)
+        let mut message = signature.authenticator_data.clone();
+        message.extend_from_array(&env.crypto().sha256(&signature.client_data_json).to_array());
+
+        env.crypto().secp256r1_verify(
+            &passkey.public_key,
+            &env.crypto().sha256(&message),
+            &signature.signature
+        );
+
+        Ok(())
+    }
+
+    /// Apply the wallet type and daily limit to SEP-41 transfer/approve/burn calls that take
+    /// tokens out of the wallet
+    fn check_token_calls(env: &Env, auth_contexts: &Vec<Context>) -> Result<(), SdkError> {
+        let wallet_address = env.current_contract_address();
+
+        for context in auth_contexts.iter() {
+            let Context::Contract(call) = context else {
+                continue;
+            };
+
+            if call.contract == wallet_address {
+                continue;
+            }
+
+            // transfer(from, to, amount), approve(from, spender, amount, expiration_ledger)
+            // and burn(from, amount)
+            let amount_index = if
+                call.fn_name == symbol_short!("transfer") ||
+                call.fn_name == symbol_short!("approve")
+            {
+                2
+            } else if call.fn_name == symbol_short!("burn") {
+                1
+            } else {
+                continue;
+            };
+
+            let from = call.args.get(0).and_then(|from| Address::try_from_val(env, &from).ok());
+
+            if from != Some(wallet_address.clone()) {
+                continue;
+            }
+
+            let amount = call.args
+                .get(amount_index)
+                .and_then(|amount| i128::try_from_val(env, &amount).ok())
+                .ok_or(SdkError::from_contract_error(ERROR_INVALID_AMOUNT))?;
+
+            // Savings wallets don't withdraw
+            let wallet_type: WalletType = env
+                .storage()
+                .instance()
+                .get(&DataKey::WalletType)
+                .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))?;
+
+            if wallet_type == WalletType::SavingsOnly {
+                return Err(SdkError::from_contract_error(ERROR_UNAUTHORIZED));
+            }
+
+            Self::check_daily_limit(env, amount)?;
+            Self::update_daily_spending(env, amount)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_signers(env: &Env) -> Result<Vec<Signer>, SdkError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Signers)
+            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))
+    }
+
     fn check_daily_limit(env: &Env, amount: i128) -> Result<(), SdkError> {
         let settings: WalletSettings = env
             .storage()
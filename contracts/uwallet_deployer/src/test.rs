@@ -1,21 +1,317 @@
 #![cfg(test)]
+extern crate std;
 
 use super::*;
-use soroban_sdk::{vec, Env, String};
+use ed25519_dalek::{ Signer as _, SigningKey };
+use p256::ecdsa::Signature as P256Signature;
+use soroban_sdk::{
+    auth::ContractContext,
+    testutils::{ Address as _, MockAuth, MockAuthInvoke },
+    token::StellarAssetClient,
+    InvokeError,
+};
+use std::format;
+
+fn passkey() -> p256::ecdsa::SigningKey {
+    p256::ecdsa::SigningKey::from_bytes(&[3; 32].into()).unwrap()
+}
+
+fn setup(env: &Env) -> (NBSWalletClient<'_>, Address) {
+    env.mock_all_auths();
+
+    let wallet = NBSWalletClient::new(env, &env.register(NBSWallet, ()));
+    let owner = Address::generate(env);
+    let point = passkey().verifying_key().to_encoded_point(false);
+    let public_key = BytesN::from_array(env, point.as_bytes().try_into().unwrap());
+    wallet.initialize(
+        &owner,
+        &Bytes::from_array(env, &[3; 16]),
+        &public_key,
+        &None,
+        &WalletType::Standard,
+        &None
+    );
+
+    (wallet, owner)
+}
+
+// Assertion of the wallet passkey with the given type and challenge
+fn passkey_sign(env: &Env, type_: &str, challenge: &[u8; 32]) -> SignerSignature {
+    let mut encoded = [0u8; 43];
+    base64_urls::encode(&mut encoded, challenge);
+    let client_data_json = format!(
+        r#"{{"type":"{}","challenge":"{}","origin":"https://app.numberspay.com"}}"#,
+        type_,
+        core::str::from_utf8(&encoded).unwrap()
+    );
+    let client_data_json = Bytes::from_slice(env, client_data_json.as_bytes());
+
+    // rpIdHash, flags with user presence and a zero counter
+    let mut authenticator_data = Bytes::from_array(env, &[0; 32]);
+    authenticator_data.extend_from_array(&[0x01, 0, 0, 0, 0]);
+
+    let mut message = [0u8; 69];
+    authenticator_data.copy_into_slice(&mut message[..37]);
+    message[37..].copy_from_slice(&env.crypto().sha256(&client_data_json).to_array());
+    let signature: P256Signature = passkey().sign(&message);
+    let signature = signature.normalize_s().unwrap_or(signature);
+
+    SignerSignature::Passkey(WebAuthnSignature {
+        authenticator_data,
+        client_data_json,
+        signature: BytesN::from_array(env, &signature.to_bytes().into()),
+    })
+}
+
+fn ed25519_signer(env: &Env, key: &SigningKey) -> Signer {
+    Signer::Ed25519(BytesN::from_array(env, &key.verifying_key().to_bytes()))
+}
+
+fn check_auth(
+    env: &Env,
+    wallet: &Address,
+    payload: &[u8; 32],
+    signature: SignerSignature
+) -> Result<(), Result<SdkError, InvokeError>> {
+    check_auth_for(env, wallet, payload, signature, Vec::new(env))
+}
+
+fn check_auth_for(
+    env: &Env,
+    wallet: &Address,
+    payload: &[u8; 32],
+    signature: SignerSignature,
+    contexts: Vec<Context>
+) -> Result<(), Result<SdkError, InvokeError>> {
+    env.try_invoke_contract_check_auth::<SdkError>(
+        wallet,
+        &BytesN::from_array(env, payload),
+        signature.into_val(env),
+        &contexts
+    )
+}
+
+fn ed25519_sign(env: &Env, key: &SigningKey, payload: &[u8; 32]) -> SignerSignature {
+    SignerSignature::Ed25519(Ed25519Signature {
+        public_key: BytesN::from_array(env, &key.verifying_key().to_bytes()),
+        signature: BytesN::from_array(env, &key.sign(payload).to_bytes()),
+    })
+}
 
 #[test]
-fn test() {
+fn owner_is_the_first_signer() {
     let env = Env::default();
-    let contract_id = env.register(Contract, ());
-    let client = ContractClient::new(&env, &contract_id);
+    let (wallet, owner) = setup(&env);
 
-    let words = client.hello(&String::from_str(&env, "Dev"));
+    assert_eq!(wallet.get_owner(), owner);
     assert_eq!(
-        words,
-        vec![
+        wallet.list_signers(),
+        Vec::from_array(
             &env,
-            String::from_str(&env, "Hello"),
-            String::from_str(&env, "Dev"),
-        ]
+            [Signer::Passkey(Bytes::from_array(&env, &[3; 16])), Signer::Account(owner.clone())]
+        )
+    );
+
+    assert_eq!(
+        wallet.try_initialize(
+            &owner,
+            &Bytes::from_array(&env, &[4; 16]),
+            &BytesN::from_array(&env, &[4; 65]),
+            &None,
+            &WalletType::Standard,
+            &None
+        ),
+        Err(Ok(SdkError::from_contract_error(ERROR_ALREADY_INITIALIZED)))
+    );
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], SignerSignature::Account(owner)),
+        Ok(())
+    );
+}
+
+#[test]
+fn added_ed25519_key_signs() {
+    let env = Env::default();
+    let (wallet, _) = setup(&env);
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let payload = [1; 32];
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, ed25519_sign(&env, &key, &payload)),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND)))
+    );
+
+    wallet.add_signer(&ed25519_signer(&env, &key));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, ed25519_sign(&env, &key, &payload)),
+        Ok(())
+    );
+    assert_eq!(
+        wallet.try_add_signer(&ed25519_signer(&env, &key)),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_EXISTS)))
+    );
+}
+
+#[test]
+fn ed25519_signature_must_match_the_payload() {
+    let env = Env::default();
+    let (wallet, _) = setup(&env);
+    let key = SigningKey::from_bytes(&[7; 32]);
+
+    wallet.add_signer(&ed25519_signer(&env, &key));
+
+    let signature = ed25519_sign(&env, &key, &[1; 32]);
+    assert!(check_auth(&env, &wallet.address, &[2; 32], signature).is_err());
+}
+
+#[test]
+fn stranger_account_is_not_a_signer() {
+    let env = Env::default();
+    let (wallet, _) = setup(&env);
+    let stranger = Address::generate(&env);
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], SignerSignature::Account(stranger)),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND)))
+    );
+}
+
+#[test]
+fn removed_signer_no_longer_signs() {
+    let env = Env::default();
+    let (wallet, owner) = setup(&env);
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let payload = [1; 32];
+
+    wallet.add_signer(&ed25519_signer(&env, &key));
+    wallet.remove_signer(&Signer::Account(owner.clone()));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, SignerSignature::Account(owner)),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND)))
+    );
+    assert_eq!(
+        wallet.try_get_owner(),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND)))
+    );
+
+    // The passkey always stays
+    wallet.remove_signer(&ed25519_signer(&env, &key));
+    assert_eq!(
+        wallet.try_remove_signer(&Signer::Passkey(Bytes::from_array(&env, &[3; 16]))),
+        Err(Ok(SdkError::from_contract_error(ERROR_INVALID_SIGNER)))
+    );
+    assert_eq!(
+        wallet.try_add_signer(&Signer::Passkey(Bytes::from_array(&env, &[4; 16]))),
+        Err(Ok(SdkError::from_contract_error(ERROR_INVALID_SIGNER)))
+    );
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, passkey_sign(&env, "webauthn.get", &payload)),
+        Ok(())
+    );
+    assert_eq!(
+        wallet.try_remove_signer(&Signer::Account(Address::generate(&env))),
+        Err(Ok(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND)))
+    );
+}
+
+#[test]
+fn uninitialized_wallet_has_no_owner() {
+    let env = Env::default();
+    let wallet = NBSWalletClient::new(&env, &env.register(NBSWallet, ()));
+
+    assert_eq!(
+        wallet.try_get_owner(),
+        Err(Ok(SdkError::from_contract_error(ERROR_NOT_INITIALIZED)))
+    );
+}
+
+#[test]
+fn passkey_assertion_must_be_for_the_payload() {
+    let env = Env::default();
+    let (wallet, _) = setup(&env);
+    let payload = [1; 32];
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, passkey_sign(&env, "webauthn.get", &payload)),
+        Ok(())
+    );
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, passkey_sign(&env, "webauthn.get", &[2; 32])),
+        Err(Ok(SdkError::from_contract_error(ERROR_CHALLENGE_MISMATCH)))
+    );
+
+    // A registration ceremony isn't an assertion
+    assert_eq!(
+        check_auth(
+            &env,
+            &wallet.address,
+            &payload,
+            passkey_sign(&env, "webauthn.create", &payload)
+        ),
+        Err(Ok(SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA)))
+    );
+}
+
+#[test]
+fn account_signer_authorizes_the_payload() {
+    let env = Env::default();
+    let (wallet, owner) = setup(&env);
+    let payload = [1; 32];
+
+    // Only an authorization of the owner for this exact payload is accepted
+    let authorize = |payload: [u8; 32]| {
+        env.mock_auths(
+            &[
+                MockAuth {
+                    address: &owner,
+                    invoke: &(MockAuthInvoke {
+                        contract: &wallet.address,
+                        fn_name: "__check_auth",
+                        args: (BytesN::from_array(&env, &payload),).into_val(&env),
+                        sub_invokes: &[],
+                    }),
+                },
+            ]
+        );
+    };
+
+    let signature = SignerSignature::Account(owner.clone());
+    authorize([2; 32]);
+    assert!(check_auth(&env, &wallet.address, &payload, signature.clone()).is_err());
+
+    authorize(payload);
+    assert_eq!(check_auth(&env, &wallet.address, &payload, signature), Ok(()));
+}
+
+#[test]
+fn direct_token_transfers_count_against_the_daily_limit() {
+    let env = Env::default();
+    let (wallet, _) = setup(&env);
+    let token = env.register_stellar_asset_contract_v2(Address::generate(&env)).address();
+    StellarAssetClient::new(&env, &token).mint(&wallet.address, &MAX_DAILY_LIMIT);
+
+    let transfer = |amount: i128| {
+        Context::Contract(ContractContext {
+            contract: token.clone(),
+            fn_name: symbol_short!("transfer"),
+            args: (wallet.address.clone(), Address::generate(&env), amount).into_val(&env),
+        })
+    };
+    let payload = [1; 32];
+    let signature = passkey_sign(&env, "webauthn.get", &payload);
+
+    let contexts = Vec::from_array(&env, [transfer(MAX_DAILY_LIMIT)]);
+    assert_eq!(
+        check_auth_for(&env, &wallet.address, &payload, signature.clone(), contexts),
+        Ok(())
+    );
+    assert_eq!(wallet.get_daily_spending(), MAX_DAILY_LIMIT);
+
+    let contexts = Vec::from_array(&env, [transfer(1)]);
+    assert_eq!(
+        check_auth_for(&env, &wallet.address, &payload, signature, contexts),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
     );
 }
@@ -0,0 +1,368 @@
+// Minimal JSON field extractor for WebAuthn `clientDataJSON`.
+//
+// Scans the document in place and returns borrowed slices for the `type`, `challenge`,
+// `origin` and `crossOrigin` members of the top level object. Nothing is allocated and
+// every other member is validated and skipped.
+//
+// The parser is deliberately strict:
+//    * The input must be a single JSON object with nothing but whitespace around it.
+//    * Top level keys must not contain escape sequences, so a field can't be smuggled in
+//      under an escaped spelling of its name.
+//    * The extracted string values must not contain escape sequences either. Browsers never
+//      need them for these fields and it means the returned slices are the exact values.
+//    * Each extracted key may only appear once.
+//    * Nesting of skipped values is bounded by `MAX_DEPTH`.
+
+const MAX_DEPTH: u32 = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientData<'a> {
+    pub type_: &'a [u8],
+    pub challenge: &'a [u8],
+    pub origin: &'a [u8],
+    pub cross_origin: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    Malformed,
+    Escaped,
+    DuplicateKey,
+    MissingField,
+}
+
+pub fn parse(json: &[u8]) -> Result<ClientData<'_>, ParseError> {
+    let mut parser = Parser { json, pos: 0 };
+
+    let mut type_ = None;
+    let mut challenge = None;
+    let mut origin = None;
+    let mut cross_origin = None;
+
+    parser.skip_whitespace();
+    parser.expect(b'{')?;
+    parser.skip_whitespace();
+
+    if !parser.eat(b'}') {
+        loop {
+            parser.skip_whitespace();
+            let key = parser.plain_string()?;
+            parser.skip_whitespace();
+            parser.expect(b':')?;
+            parser.skip_whitespace();
+
+            match key {
+                b"type" => set_once(&mut type_, parser.plain_string()?)?,
+                b"challenge" => set_once(&mut challenge, parser.plain_string()?)?,
+                b"origin" => set_once(&mut origin, parser.plain_string()?)?,
+                b"crossOrigin" => set_once(&mut cross_origin, parser.boolean()?)?,
+                _ => parser.skip_value(0)?,
+            }
+
+            parser.skip_whitespace();
+
+            if parser.eat(b',') {
+                continue;
+            }
+
+            parser.expect(b'}')?;
+            break;
+        }
+    }
+
+    parser.skip_whitespace();
+
+    if parser.pos != json.len() {
+        return Err(ParseError::Malformed);
+    }
+
+    Ok(ClientData {
+        type_: type_.ok_or(ParseError::MissingField)?,
+        challenge: challenge.ok_or(ParseError::MissingField)?,
+        origin: origin.ok_or(ParseError::MissingField)?,
+        cross_origin,
+    })
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T) -> Result<(), ParseError> {
+    if slot.is_some() {
+        return Err(ParseError::DuplicateKey);
+    }
+
+    *slot = Some(value);
+    Ok(())
+}
+
+struct Parser<'a> {
+    json: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.json.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, ParseError> {
+        let byte = self.peek().ok_or(ParseError::Malformed)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ParseError> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(ParseError::Malformed)
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &[u8]) -> Result<(), ParseError> {
+        let end = self.pos + literal.len();
+
+        if self.json.get(self.pos..end) != Some(literal) {
+            return Err(ParseError::Malformed);
+        }
+
+        self.pos = end;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// A string that must not contain any escape sequence, returned without its quotes
+    fn plain_string(&mut self) -> Result<&'a [u8], ParseError> {
+        self.expect(b'"')?;
+        let start = self.pos;
+
+        loop {
+            match self.next()? {
+                b'"' => {
+                    return Ok(&self.json[start..self.pos - 1]);
+                }
+                b'\\' => {
+                    return Err(ParseError::Escaped);
+                }
+                0x00..=0x1f => {
+                    return Err(ParseError::Malformed);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn boolean(&mut self) -> Result<bool, ParseError> {
+        match self.peek() {
+            Some(b't') => self.expect_literal(b"true").map(|_| true),
+            Some(b'f') => self.expect_literal(b"false").map(|_| false),
+            _ => Err(ParseError::Malformed),
+        }
+    }
+
+    fn skip_string(&mut self) -> Result<(), ParseError> {
+        self.expect(b'"')?;
+
+        loop {
+            match self.next()? {
+                b'"' => {
+                    return Ok(());
+                }
+                b'\\' => match self.next()? {
+                    b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => {}
+                    b'u' => {
+                        for _ in 0..4 {
+                            if !self.next()?.is_ascii_hexdigit() {
+                                return Err(ParseError::Malformed);
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(ParseError::Malformed);
+                    }
+                },
+                0x00..=0x1f => {
+                    return Err(ParseError::Malformed);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn skip_digits(&mut self) -> Result<(), ParseError> {
+        if !self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::Malformed);
+        }
+
+        while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            self.pos += 1;
+        }
+
+        Ok(())
+    }
+
+    fn skip_number(&mut self) -> Result<(), ParseError> {
+        self.eat(b'-');
+
+        if !self.eat(b'0') {
+            self.skip_digits()?;
+        }
+
+        if self.eat(b'.') {
+            self.skip_digits()?;
+        }
+
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            self.skip_digits()?;
+        }
+
+        Ok(())
+    }
+
+    fn skip_value(&mut self, depth: u32) -> Result<(), ParseError> {
+        match self.peek().ok_or(ParseError::Malformed)? {
+            b'"' => self.skip_string(),
+            b't' => self.expect_literal(b"true"),
+            b'f' => self.expect_literal(b"false"),
+            b'n' => self.expect_literal(b"null"),
+            b'-' | b'0'..=b'9' => self.skip_number(),
+            b'{' => self.skip_container(depth, b'}', true),
+            b'[' => self.skip_container(depth, b']', false),
+            _ => Err(ParseError::Malformed),
+        }
+    }
+
+    fn skip_container(&mut self, depth: u32, close: u8, keyed: bool) -> Result<(), ParseError> {
+        if depth >= MAX_DEPTH {
+            return Err(ParseError::Malformed);
+        }
+
+        // Consume the opening bracket
+        self.pos += 1;
+        self.skip_whitespace();
+
+        if self.eat(close) {
+            return Ok(());
+        }
+
+        loop {
+            self.skip_whitespace();
+
+            if keyed {
+                self.skip_string()?;
+                self.skip_whitespace();
+                self.expect(b':')?;
+                self.skip_whitespace();
+            }
+
+            self.skip_value(depth + 1)?;
+            self.skip_whitespace();
+
+            if self.eat(b',') {
+                continue;
+            }
+
+            return self.expect(close);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALID: &[u8] =
+        br#"{"type":"webauthn.get","challenge":"AAEC","origin":"https://a.b","crossOrigin":false}"#;
+
+    #[test]
+    fn extracts_fields() {
+        let client_data = parse(VALID).unwrap();
+
+        assert_eq!(client_data.type_, b"webauthn.get");
+        assert_eq!(client_data.challenge, b"AAEC");
+        assert_eq!(client_data.origin, b"https://a.b");
+        assert_eq!(client_data.cross_origin, Some(false));
+    }
+
+    #[test]
+    fn skips_other_members() {
+        let json = br#" {"other":{"a":[1,-2.5e3,true,null,"x\"y\u00e9"]},"type":"t",
+            "challenge":"c","origin":"o","tokenBinding":{"status":"present"}} "#;
+        let client_data = parse(json).unwrap();
+
+        assert_eq!(client_data.type_, b"t");
+        assert_eq!(client_data.cross_origin, None);
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        let json = br#"{"type":"webauthn.get","challenge":"a","origin":"o","challenge":"b"}"#;
+        assert_eq!(parse(json), Err(ParseError::DuplicateKey));
+
+        let json = br#"{"type":"t","challenge":"c","origin":"o","crossOrigin":false,
+            "crossOrigin":true}"#;
+        assert_eq!(parse(json), Err(ParseError::DuplicateKey));
+    }
+
+    #[test]
+    fn rejects_escape_sequences() {
+        // An escaped key could hide a second "challenge"
+        let json = br#"{"type":"t","challenge":"a","origin":"o","ch\u0061llenge":"b"}"#;
+        assert_eq!(parse(json), Err(ParseError::Escaped));
+
+        let json = br#"{"type":"t","challenge":"a","origin":"https:\/\/evil"}"#;
+        assert_eq!(parse(json), Err(ParseError::Escaped));
+    }
+
+    #[test]
+    fn bounds_nesting_depth() {
+        let json = br#"{"type":"t","challenge":"c","origin":"o","x":[[[[[[[[]]]]]]]]}"#;
+        assert!(parse(json).is_ok());
+
+        let json = br#"{"type":"t","challenge":"c","origin":"o","x":[[[[[[[[[]]]]]]]]]}"#;
+        assert_eq!(parse(json), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn requires_fields() {
+        let json = br#"{"type":"t","challenge":"c"}"#;
+        assert_eq!(parse(json), Err(ParseError::MissingField));
+
+        // A wrongly typed field isn't skipped as something else
+        let json = br#"{"type":1,"challenge":"c","origin":"o"}"#;
+        assert_eq!(parse(json), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        let documents: [&[u8]; 8] = [
+            b"",
+            b"[]",
+            br#"{"type":"t","challenge":"c","origin":"o"} {}"#,
+            br#"{"type":"t","challenge":"c","origin":"o",}"#,
+            br#"{"type":"t" "challenge":"c","origin":"o"}"#,
+            br#"{"type":"t","challenge":"c","origin":"o","crossOrigin":"true"}"#,
+            br#"{"type":"t","challenge":"c","origin":"o","x":01}"#,
+            b"{\"type\":\"t\",\"challenge\":\"c\",\"origin\":\"o\n\"}",
+        ];
+
+        for json in documents {
+            assert_eq!(parse(json), Err(ParseError::Malformed));
+        }
+    }
+}
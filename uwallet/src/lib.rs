@@ -1,27 +1,43 @@
 #![no_std]
 
 use soroban_sdk::{
+    auth::{ Context, ContractContext },
     contract,
+    contractclient,
     contractimpl,
     contracttype,
+    crypto::Hash,
     symbol_short,
     token::{ self },
+    xdr::ToXdr,
     Address,
     Bytes,
     BytesN,
     Env,
     Error as SdkError,
+    IntoVal,
+    Map,
     Symbol,
+    TryFromVal,
     Vec,
 };
 
 mod base64_urls;
+mod client_data;
+mod secp256r1;
+mod test;
 
 // Constants
 const WEEK_OF_LEDGERS: u32 = ((60 * 60 * 24) / 5) * 7;
 const EVENT_TAG: Symbol = symbol_short!("NBSWALLET");
-const MAX_DAILY_LIMIT: i128 = 10_000_0000000; // $10,000 with 7 decimals
+const MAX_DAILY_LIMIT: i128 = 10_000_0000000; // $10,000 with 7 decimals, for tokens without a limit
 const RECOVERY_DELAY: u64 = ((60 * 60 * 24) / 5) * 7; // 1 week in ledgers
+const LOGIN_DOMAIN: &[u8] = b"nbswallet:login"; // Prefix of `verify_message` challenges
+const MAX_CLIENT_DATA_LEN: u32 = 1024;
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+const MAX_UTC_OFFSET: i64 = 14 * 60 * 60; // UTC+14, the furthest time zone from UTC
+const MAX_PENDING_TRANSFERS: u32 = 10;
 
 // Error codes
 const ERROR_ALREADY_INITIALIZED: u32 = 1;
@@ -33,6 +49,52 @@ const ERROR_UNAUTHORIZED: u32 = 6;
 const ERROR_INVALID_SIGNATURE: u32 = 7;
 const ERROR_RECOVERY_PENDING: u32 = 8;
 const ERROR_NO_RECOVERY_PENDING: u32 = 9;
+const ERROR_CHALLENGE_MISMATCH: u32 = 10;
+const ERROR_INVALID_CLIENT_DATA: u32 = 11;
+const ERROR_ORIGIN_NOT_ALLOWED: u32 = 12;
+const ERROR_NO_ORIGINS: u32 = 13;
+const ERROR_RP_ID_MISMATCH: u32 = 14;
+const ERROR_USER_VERIFICATION_REQUIRED: u32 = 15;
+const ERROR_SIGN_COUNT_REPLAY: u32 = 16; // Possible cloned passkey, see `verify_assertion`
+const ERROR_PASSKEY_EXISTS: u32 = 17;
+const ERROR_PASSKEY_NOT_FOUND: u32 = 18;
+const ERROR_LAST_PASSKEY: u32 = 19;
+const ERROR_SIGNER_EXISTS: u32 = 20;
+const ERROR_SIGNER_NOT_FOUND: u32 = 21;
+const ERROR_INVALID_SIGNER: u32 = 22;
+const ERROR_PERMISSION_DENIED: u32 = 23;
+const ERROR_SESSION_NOT_FOUND: u32 = 24;
+const ERROR_SESSION_EXPIRED: u32 = 25;
+const ERROR_SESSION_BUDGET_EXCEEDED: u32 = 26;
+const ERROR_SESSION_EXISTS: u32 = 27;
+const ERROR_DUPLICATE_SIGNATURE: u32 = 28;
+const ERROR_THRESHOLD_NOT_MET: u32 = 29;
+const ERROR_INVALID_THRESHOLD: u32 = 30;
+const ERROR_POLICY_NOT_APPROVED: u32 = 31;
+const ERROR_POLICY_EXISTS: u32 = 32;
+const ERROR_POLICY_NOT_FOUND: u32 = 33;
+const ERROR_POLICY_REJECTED: u32 = 34;
+const ERROR_NO_PENDING_CHANGE: u32 = 35;
+const ERROR_INVALID_PUBLIC_KEY: u32 = 36;
+const ERROR_FEE_TOO_HIGH: u32 = 37;
+const ERROR_INVALID_UTC_OFFSET: u32 = 38;
+const ERROR_WEEKLY_LIMIT_EXCEEDED: u32 = 39;
+const ERROR_MONTHLY_LIMIT_EXCEEDED: u32 = 40;
+const ERROR_NO_PRICE_SOURCE: u32 = 41;
+const ERROR_CONTACT_EXISTS: u32 = 42;
+const ERROR_CONTACT_NOT_FOUND: u32 = 43;
+const ERROR_HOURLY_TX_LIMIT: u32 = 44;
+const ERROR_DAILY_TX_LIMIT: u32 = 45;
+const ERROR_NEW_RECIPIENT_LIMIT: u32 = 46;
+const ERROR_STEP_UP_REQUIRED: u32 = 47;
+const ERROR_PENDING_NOT_FOUND: u32 = 48;
+const ERROR_PENDING_EXPIRED: u32 = 49;
+const ERROR_TOO_MANY_PENDING: u32 = 50;
+const ERROR_GUARDIAN_EXISTS: u32 = 51;
+const ERROR_GUARDIAN_NOT_FOUND: u32 = 52;
+const ERROR_SELF_APPROVAL: u32 = 53;
+const ERROR_APPROVER_TOO_NEW: u32 = 54;
+const ERROR_ALREADY_APPROVED: u32 = 55;
 
 // Data structures
 #[contracttype]
@@ -41,21 +103,202 @@ pub struct PasskeyCredential {
     pub id: Bytes,
     pub public_key: BytesN<65>,
     pub created_at: u64,
+    pub sign_count: u32, // last counter seen, stays 0 for authenticators without one
 }
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct WebAuthnSignature {
+    pub id: Bytes, // credential id of the passkey that signed
     pub authenticator_data: Bytes,
     pub client_data_json: Bytes,
+    pub signature: Bytes, // 64 byte r || s or ASN.1 DER, either S value
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ed25519Signature {
+    pub public_key: BytesN<32>,
     pub signature: BytesN<64>,
 }
 
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Signer {
+    Passkey(Bytes),      // Credential id of a passkey in DataKey::Passkeys
+    Ed25519(BytesN<32>), // Raw Ed25519 public key, e.g. a backend hot key
+    Account(Address),    // Any Stellar account or contract, e.g. a hardware wallet
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SignerSignature {
+    Passkey(WebAuthnSignature),
+    Ed25519(Ed25519Signature),
+    Account(Address), // The account authorizes the signature payload through its own auth
+    Session(Ed25519Signature), // Signed by a session key instead of a wallet signer
+}
+
+// Short lived Ed25519 key for frequent in-app payments without a passkey prompt
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionKey {
+    pub public_key: BytesN<32>,
+    pub expires_at: u32, // Last ledger sequence the key can be used in
+    pub budgets: Map<Address, i128>, // Token -> amount the key can still spend
+    pub functions: Vec<Symbol>, // Payment functions of the wallet the key may call
+}
+
+// What a signer may authorize. Signers without a permission set are unrestricted, restricted
+// signers can only call the wallet's own functions that are listed in `functions`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignerPermissions {
+    pub contracts: Option<Vec<Address>>, // Other contracts that may be called, None for any
+    pub functions: Option<Vec<Symbol>>, // Functions that may be called, None for any
+    pub amount_caps: Map<Address, i128>, // Token -> max amount moved by a single call
+}
+
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DailySpending {
-    pub date: u64,
+    pub hours: Map<u64, i128>, // UTC hour -> amount spent in it, only the last 25 hours are kept
+    pub days: Map<u64, i128>, // UTC day -> amount spent in it, only the last 30 days are kept
+}
+
+// Caps for one token, in the token's own units
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpendingLimits {
+    pub daily: i128,
+    pub weekly: Option<i128>, // Over the last 7 calendar days, None for no cap
+    pub monthly: Option<i128>, // Over the last 30 calendar days, None for no cap
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LimitScope {
+    Token(Address), // Limits of a single token, in its own units
+    Base,           // Limits across all tokens, in the base currency
+}
+
+// Limit increase waiting out the wallet's limit delay, in force from `effective_at`
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingLimits {
+    pub limits: SpendingLimits,
+    pub effective_at: u64,
+}
+
+// Spending window change that could shorten the current window, in force from `effective_at`
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingSpendingWindow {
+    pub window: SpendingWindow,
+    pub effective_at: u64,
+}
+
+// Shorter limit delay, lowering it is time locked by the current delay too
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingLimitDelay {
+    pub delay: u64,
+    pub effective_at: u64,
+}
+
+// Address book entry. Once trusted, sends to the contact skip the wallet's limits and only count
+// against the contact's own daily limit for the token, if it has one.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Contact {
+    pub address: Address,
+    pub nickname_hash: BytesN<32>, // SHA-256 of the nickname, the name itself stays off chain
+    pub limits: Map<Address, i128>, // Token -> daily limit for sends to the contact
+    pub added_at: u64,
+    pub trusted_at: u64, // Until then the contact is treated like any other recipient
+}
+
+// Limits on how fast money can leave the wallet, against bursts of payments to new recipients
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VelocityRules {
+    pub max_per_hour: Option<u32>, // Outgoing payments in the current hour, None for no cap
+    pub max_per_day: Option<u32>, // Outgoing payments in the last 24 hours, None for no cap
+    pub new_recipient_period: u64, // Seconds a recipient counts as new after the first payment
+    pub new_recipient_limits: Map<Address, i128>, // Token -> max sent to a new recipient
+}
+
+// Velocity rules that loosen the current ones, in force from `effective_at`
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingVelocity {
+    pub rules: VelocityRules,
+    pub effective_at: u64,
+}
+
+// Remembered for every recipient the wallet has paid
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecipientRecord {
+    pub first_paid_at: u64,
+    pub sent: Map<Address, i128>, // Token -> amount sent while the recipient was new
+}
+
+// Transfers above the token's threshold wait for a second passkey or a guardian
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StepUpPolicy {
+    pub thresholds: Map<Address, i128>, // Token -> largest amount that executes right away
+    pub approval_window: u64, // Seconds a pending transfer can be approved in
+}
+
+// Step-up policy that loosens the current one, in force from `effective_at`
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingStepUp {
+    pub policy: StepUpPolicy,
+    pub effective_at: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingTransfer {
+    pub id: u32,
+    pub to: Address,
+    pub token: Address,
     pub amount: i128,
+    pub value: i128, // Base currency value held against the base limits, 0 without them
+    pub initiators: Vec<Signer>, // Signers of the transfer, they can't approve it themselves
+    pub created_at: u64,
+    pub deadline: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum StepUpApproval {
+    // Assertion over sha256(xdr(("approve", wallet, id))) from a passkey that didn't initiate.
+    // Passkeys and guardians only approve transfers created a full limit delay after they were
+    // added, so a stolen signer can't enroll its own approver.
+    Passkey(WebAuthnSignature),
+    Guardian(Address),
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LimitWindow {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpendingStatus {
+    pub window: LimitWindow,
+    pub spent: i128,
+    pub limit: i128,
+    pub remaining: i128,
 }
 
 #[contracttype]
@@ -81,25 +324,104 @@ pub struct Signature {
 pub struct RecoveryRequest {
     pub new_passkey: PasskeyCredential,
     pub requested_at: u64,
-    pub expires_at: u64,
+    pub approvals: Vec<Address>, // Guardians that approved the request
+    pub executable_at: Option<u64>, // Set once enough guardians approved, RECOVERY_DELAY later
 }
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum DataKey {
-    Passkey,
-    DailySpending,
+    Passkeys, // Map of credential id -> PasskeyCredential
+    Signers, // Vec of the non passkey signers
+    Permissions(Signer), // Map of signer -> SignerPermissions
+    SessionKey(BytesN<32>), // Map of session public key -> SessionKey
+    SessionKeys, // Vec of session public keys, so recovery can drop them all
+    Policies, // Vec of policy contracts consulted on every authorization
+    PolicyRemovals, // Map of policy -> time its queued removal takes effect
+    UserManager, // UserManager whose registry vets policy contracts
+    DailySpending(Address), // Map of token -> DailySpending
+    BaseLimits, // SpendingLimits in the base currency, valued through the UserManager's oracle
+    BaseSpending, // DailySpending in the base currency
+    PendingLimits(LimitScope), // Map of limit scope -> PendingLimits
+    PendingLimitDelay,
+    PendingSpendingWindow,
+    Contacts, // Map of address -> Contact
+    ContactSpending(Address, Address), // Map of (contact, token) -> DailySpending
+    PendingVelocity,
+    PaymentCounts, // Map of hour -> outgoing payments, last 24 hours only
+    Recipient(Address), // Map of recipient -> RecipientRecord, in persistent storage
+    Guardians, // Map of guardian -> time it was added, guardians approve step-up and recovery
+    PendingStepUp,
+    PendingTransfers, // Map of id -> PendingTransfer
+    NextPendingId,
+    AuthSigners, // Signers of the last outgoing transfer, in temporary storage
     Recovery,
     TransactionHistory,
     Settings,
 }
 
+// Spending is bucketed by UTC hour whatever the mode, so switching mode or offset never
+// forgets what was spent. It can still shorten the current window, which is time locked.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SpendingWindow {
+    Rolling,       // The last 24 hours, in hourly buckets
+    Calendar(i64), // The current day, starting at midnight in the given UTC offset (seconds)
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UserVerification {
+    Always,            // Every assertion must have the UV flag set
+    AboveAmount(i128), // Only send/withdraw totals above the amount need UV
+    Never,             // User presence is enough
+}
+
+// M-of-N signatures needed to authorize an invocation, e.g. for business wallets.
+// A threshold of 1 without overrides is a plain single signature wallet.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ThresholdPolicy {
+    pub threshold: u32, // Signatures needed unless a function override applies
+    pub functions: Map<Symbol, u32>, // Wallet function -> signatures needed
+}
+
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct WalletSettings {
-    pub daily_limit: i128,
-    pub recovery_enabled: bool,
+    pub limits: Map<Address, SpendingLimits>, // Token -> spending limits
+    pub spending_window: SpendingWindow,
+    pub limit_delay: u64, // Seconds before a limit increase takes effect
+    pub velocity: VelocityRules,
+    pub step_up: StepUpPolicy,
+    pub recovery_threshold: u32, // Guardian approvals a recovery needs, 0 while none are set
     pub created_at: u64,
+    pub allowed_origins: Vec<Bytes>,
+    pub rp_id: Bytes,
+    pub user_verification: UserVerification,
+    pub threshold: ThresholdPolicy,
+}
+
+// External rule set (merchant allowlists, blocked countries, ...) consulted on every
+// authorization. `signers` is empty when a session key signed.
+#[contractclient(name = "PolicyClient")]
+pub trait Policy {
+    /// Return an error to deny the authorization
+    fn check(
+        env: Env,
+        wallet: Address,
+        contexts: Vec<Context>,
+        signers: Vec<Signer>
+    ) -> Result<(), SdkError>;
+}
+
+#[contractclient(name = "UserManagerClient")]
+pub trait UserManager {
+    /// Check if a policy contract runs an approved policy WASM
+    fn is_policy_approved(env: Env, policy: Address) -> bool;
+
+    /// Value of a token amount in the base currency, with 7 decimals
+    fn value_of(env: Env, token: Address, amount: i128) -> Result<i128, SdkError>;
 }
 
 #[contract]
@@ -108,35 +430,84 @@ pub struct NBSWallet;
 #[contractimpl]
 impl NBSWallet {
     /// Initialize a new mobile banking wallet with passkey
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         env: Env,
         passkey_id: Bytes,
-        public_key: BytesN<65>,
-        daily_limit: Option<i128>
+        public_key: Bytes, // SEC1 P-256 key, uncompressed (65 bytes) or compressed (33 bytes)
+        limits: Map<Address, SpendingLimits>,
+        allowed_origins: Vec<Bytes>,
+        rp_id: Bytes,
+        owner: Option<Address>,
+        user_manager: Option<Address>
     ) -> Result<(), SdkError> {
         // Check if wallet is already initialized
-        if env.storage().instance().has(&DataKey::Passkey) {
+        if env.storage().instance().has(&DataKey::Passkeys) {
             return Err(SdkError::from_contract_error(ERROR_ALREADY_INITIALIZED));
         }
 
+        // Passkeys can't authorize anything without at least one allowed origin
+        if allowed_origins.is_empty() {
+            return Err(SdkError::from_contract_error(ERROR_NO_ORIGINS));
+        }
+
+        for token_limits in limits.values().iter() {
+            Self::check_limits_valid(&token_limits)?;
+        }
+
         // Create initial passkey credential
         let passkey = PasskeyCredential {
             id: passkey_id.clone(),
-            public_key,
+            public_key: Self::parse_public_key(&env, &public_key)?,
             created_at: env.ledger().timestamp(),
+            sign_count: 0,
         };
 
         // Set wallet settings
         let settings = WalletSettings {
-            daily_limit: daily_limit.unwrap_or(MAX_DAILY_LIMIT),
-            recovery_enabled: true,
+            limits,
+            spending_window: SpendingWindow::Rolling,
+            limit_delay: SECONDS_PER_DAY,
+            velocity: VelocityRules {
+                max_per_hour: None,
+                max_per_day: None,
+                new_recipient_period: 0,
+                new_recipient_limits: Map::new(&env),
+            },
+            step_up: StepUpPolicy {
+                thresholds: Map::new(&env),
+                approval_window: SECONDS_PER_DAY,
+            },
+            recovery_threshold: 0,
             created_at: env.ledger().timestamp(),
+            allowed_origins,
+            rp_id,
+            user_verification: UserVerification::Always,
+            threshold: ThresholdPolicy {
+                threshold: 1,
+                functions: Map::new(&env),
+            },
         };
 
         // Store data
-        env.storage().instance().set(&DataKey::Passkey, &passkey);
+        let mut passkeys = Map::<Bytes, PasskeyCredential>::new(&env);
+        passkeys.set(passkey_id.clone(), passkey);
+        env.storage().instance().set(&DataKey::Passkeys, &passkeys);
         env.storage().instance().set(&DataKey::Settings, &settings);
 
+        // The owner account, if any, can act as an extra or backup signer
+        let mut signers = Vec::<Signer>::new(&env);
+        if let Some(owner) = owner {
+            signers.push_back(Signer::Account(owner));
+        }
+        env.storage().instance().set(&DataKey::Signers, &signers);
+
+        // Policies can only be attached once vetted by the UserManager
+        if let Some(user_manager) = user_manager {
+            env.storage().instance().set(&DataKey::UserManager, &user_manager);
+        }
+        env.storage().instance().set(&DataKey::Policies, &Vec::<Address>::new(&env));
+
         // Initialize empty transaction history
         let history = Vec::<Transaction>::new(&env);
         env.storage().instance().set(&DataKey::TransactionHistory, &history);
@@ -162,7 +533,7 @@ impl NBSWallet {
         }
 
         // Require wallet initialization
-        if !env.storage().instance().has(&DataKey::Passkey) {
+        if !env.storage().instance().has(&DataKey::Passkeys) {
             return Err(SdkError::from_contract_error(ERROR_NOT_INITIALIZED));
         }
 
@@ -192,15 +563,21 @@ impl NBSWallet {
         }
 
         // Require wallet initialization
-        if !env.storage().instance().has(&DataKey::Passkey) {
+        if !env.storage().instance().has(&DataKey::Passkeys) {
             return Err(SdkError::from_contract_error(ERROR_NOT_INITIALIZED));
         }
 
         // Require authentication (handled by __check_auth)
         env.current_contract_address().require_auth();
 
+        // Large withdrawals wait for a second approval
+        if Self::requires_step_up(&env, &token, amount)? {
+            return Self::create_pending(&env, destination, token, amount);
+        }
+
         // Check daily spending limit
-        Self::check_daily_limit(&env, amount)?;
+        let value = Self::check_daily_limit(&env, &token, amount)?;
+        Self::check_velocity(&env, Some(&destination), &token, amount)?;
 
         // Check balance
         let wallet_address = env.current_contract_address();
@@ -214,7 +591,7 @@ impl NBSWallet {
         token::Client::new(&env, &token).transfer(&wallet_address, &destination, &amount);
 
         // Update daily spending
-        Self::update_daily_spending(&env, amount)?;
+        Self::update_daily_spending(&env, &token, amount, value)?;
 
         // Emit event
         env.events().publish((EVENT_TAG, symbol_short!("withdraw")), (destination, token, amount));
@@ -236,8 +613,23 @@ impl NBSWallet {
         // Require authentication
         env.current_contract_address().require_auth();
 
+        // Large transfers wait for a second approval, even to trusted contacts
+        if Self::requires_step_up(&env, &token, amount)? {
+            return Self::create_pending(&env, to_wallet, token, amount);
+        }
+
+        // Trusted contacts have their own rule instead of the wallet's limits
+        let contact = Self::trusted_contact(&env, &to_wallet);
+
         // Check daily spending limit
-        Self::check_daily_limit(&env, amount)?;
+        let value = match &contact {
+            Some(contact) => {
+                Self::check_contact_limit(&env, contact, &token, amount)?;
+                None
+            }
+            None => Self::check_daily_limit(&env, &token, amount)?,
+        };
+        Self::check_velocity(&env, Some(&to_wallet), &token, amount)?;
 
         // Check balance
         let wallet_address = env.current_contract_address();
@@ -251,7 +643,13 @@ impl NBSWallet {
         token::Client::new(&env, &token).transfer(&wallet_address, &to_wallet, &amount);
 
         // Update daily spending
-        Self::update_daily_spending(&env, amount)?;
+        match &contact {
+            Some(contact) => {
+                let key = DataKey::ContactSpending(contact.address.clone(), token.clone());
+                Self::record_spending(&env, &key, amount)?;
+            }
+            None => Self::update_daily_spending(&env, &token, amount, value)?,
+        }
 
         // Record transaction
         Self::record_transaction(&env, wallet_address, to_wallet.clone(), token.clone(), amount)?;
@@ -262,212 +660,2749 @@ impl NBSWallet {
         Ok(())
     }
 
+    /// Send tokens and reimburse the relayer that submitted the transaction in the same token.
+    /// Signers approve `max_fee` rather than `fee`, so the relayer picks the fee when
+    /// submitting but can never charge more than was signed.
+    pub fn send_with_fee(
+        env: Env,
+        to_wallet: Address,
+        token: Address,
+        amount: i128,
+        relayer: Address,
+        fee: i128,
+        max_fee: i128
+    ) -> Result<(), SdkError> {
+        if amount <= 0 || fee < 0 {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+        }
+
+        if fee > max_fee {
+            return Err(SdkError::from_contract_error(ERROR_FEE_TOO_HIGH));
+        }
+
+        // Require authentication for everything except the fee the relayer picked
+        env.current_contract_address().require_auth_for_args(
+            (to_wallet.clone(), token.clone(), amount, relayer.clone(), max_fee).into_val(&env)
+        );
+
+        // The fee leaves the wallet too, so it counts against the step-up threshold and the
+        // daily limit
+        let total = amount
+            .checked_add(fee)
+            .ok_or(SdkError::from_contract_error(ERROR_INVALID_AMOUNT))?;
+
+        // Relayed payments execute right away, large ones have to go through `send`
+        if Self::requires_step_up(&env, &token, total)? {
+            return Err(SdkError::from_contract_error(ERROR_STEP_UP_REQUIRED));
+        }
+
+        // Check daily spending limit
+        let value = Self::check_daily_limit(&env, &token, total)?;
+        Self::check_velocity(&env, Some(&to_wallet), &token, amount)?;
+
+        // Check balance
+        let wallet_address = env.current_contract_address();
+        let token_client = token::Client::new(&env, &token);
+
+        if token_client.balance(&wallet_address) < total {
+            return Err(SdkError::from_contract_error(ERROR_INSUFFICIENT_BALANCE));
+        }
+
+        // Transfer tokens and the relayer's fee
+        token_client.transfer(&wallet_address, &to_wallet, &amount);
+
+        if fee > 0 {
+            token_client.transfer(&wallet_address, &relayer, &fee);
+        }
+
+        // Update daily spending
+        Self::update_daily_spending(&env, &token, total, value)?;
+
+        // Record transactions
+        Self::record_transaction(
+            &env,
+            wallet_address.clone(),
+            to_wallet.clone(),
+            token.clone(),
+            amount
+        )?;
+
+        if fee > 0 {
+            Self::record_transaction(&env, wallet_address, relayer.clone(), token.clone(), fee)?;
+        }
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("send_fee")),
+            (to_wallet, token, amount, relayer, fee)
+        );
+
+        Ok(())
+    }
+
     /// Get token balance
     pub fn balance(env: Env, token: Address) -> i128 {
         let wallet_address = env.current_contract_address();
         token::Client::new(&env, &token).balance(&wallet_address)
     }
 
-    /// Update passkey (for device migration)
-    pub fn update_passkey(
+    /// Register an additional passkey (e.g. a second device)
+    pub fn add_passkey(
         env: Env,
-        new_passkey_id: Bytes,
-        new_public_key: BytesN<65>
+        passkey_id: Bytes,
+        public_key: Bytes
     ) -> Result<(), SdkError> {
-        // Require authentication with current passkey
+        // Require authentication with a current passkey
         env.current_contract_address().require_auth();
 
-        let new_passkey = PasskeyCredential {
-            id: new_passkey_id.clone(),
-            public_key: new_public_key,
-            created_at: env.ledger().timestamp(),
-        };
+        Self::store_passkey(&env, passkey_id.clone(), public_key)?;
 
-        env.storage().instance().set(&DataKey::Passkey, &new_passkey);
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("key_add")), passkey_id);
 
-        // Extend TTL
-        let max_ttl = env.storage().max_ttl();
-        env.storage()
-            .instance()
-            .extend_ttl(max_ttl - WEEK_OF_LEDGERS, max_ttl);
+        Ok(())
+    }
+
+    /// Remove a passkey, the last remaining one can't be removed
+    pub fn remove_passkey(env: Env, passkey_id: Bytes) -> Result<(), SdkError> {
+        // Require authentication with a current passkey
+        env.current_contract_address().require_auth();
+
+        Self::delete_passkey(&env, &passkey_id)?;
 
         // Emit event
-        env.events().publish((EVENT_TAG, symbol_short!("put_key")), new_passkey_id);
+        env.events().publish((EVENT_TAG, symbol_short!("key_rm")), passkey_id);
 
         Ok(())
     }
 
-    /// Initiate recovery process
-    pub fn initiate_recovery(
+    /// Get all passkeys registered on the wallet
+    pub fn list_passkeys(env: Env) -> Result<Vec<PasskeyCredential>, SdkError> {
+        Ok(Self::get_passkeys(&env)?.values())
+    }
+
+    /// Update passkey (for device migration), adds the new passkey then removes the old one
+    pub fn update_passkey(
         env: Env,
+        old_passkey_id: Bytes,
         new_passkey_id: Bytes,
-        new_public_key: BytesN<65>
+        new_public_key: Bytes
     ) -> Result<(), SdkError> {
-        let settings: WalletSettings = env
-            .storage()
-            .instance()
-            .get(&DataKey::Settings)
-            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))?;
+        // Require authentication with a current passkey
+        env.current_contract_address().require_auth();
 
-        if !settings.recovery_enabled {
-            return Err(SdkError::from_contract_error(ERROR_UNAUTHORIZED));
+        Self::store_passkey(&env, new_passkey_id.clone(), new_public_key)?;
+        Self::delete_passkey(&env, &old_passkey_id)?;
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("put_key")),
+            (old_passkey_id, new_passkey_id)
+        );
+
+        Ok(())
+    }
+
+    /// Register an Ed25519 key or Stellar account as an additional signer
+    pub fn add_signer(env: Env, signer: Signer) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        // Passkeys carry a public key and are managed through add_passkey
+        if let Signer::Passkey(_) = signer {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNER));
         }
 
-        let new_passkey = PasskeyCredential {
-            id: new_passkey_id.clone(),
-            public_key: new_public_key,
-            created_at: env.ledger().timestamp(),
-        };
+        let mut signers = Self::get_signers(&env);
 
-        let recovery_request = RecoveryRequest {
-            new_passkey: new_passkey.clone(),
-            requested_at: env.ledger().timestamp(),
-            expires_at: env.ledger().timestamp() + RECOVERY_DELAY,
-        };
+        if signers.contains(&signer) {
+            return Err(SdkError::from_contract_error(ERROR_SIGNER_EXISTS));
+        }
 
-        env.storage().instance().set(&DataKey::Recovery, &recovery_request);
+        signers.push_back(signer.clone());
+        env.storage().instance().set(&DataKey::Signers, &signers);
 
         // Emit event
-        env.events().publish((EVENT_TAG, symbol_short!("reco_init")), new_passkey_id);
+        env.events().publish((EVENT_TAG, symbol_short!("sig_add")), signer);
 
         Ok(())
     }
 
-    /// Complete recovery process
-    pub fn complete_recovery(env: Env) -> Result<(), SdkError> {
-        let recovery_request: RecoveryRequest = env
-            .storage()
-            .instance()
-            .get(&DataKey::Recovery)
-            .ok_or(SdkError::from_contract_error(ERROR_NO_RECOVERY_PENDING))?;
+    /// Remove an Ed25519 key or Stellar account signer
+    pub fn remove_signer(env: Env, signer: Signer) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
 
-        if env.ledger().timestamp() < recovery_request.expires_at {
-            return Err(SdkError::from_contract_error(ERROR_RECOVERY_PENDING));
+        if let Signer::Passkey(_) = signer {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNER));
         }
 
-        // Update passkey
-        env.storage().instance().set(&DataKey::Passkey, &recovery_request.new_passkey);
+        let mut signers = Self::get_signers(&env);
 
-        // Remove recovery request
-        env.storage().instance().remove(&DataKey::Recovery);
+        let index = signers
+            .first_index_of(&signer)
+            .ok_or(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND))?;
+
+        signers.remove(index);
+        env.storage().instance().set(&DataKey::Signers, &signers);
+
+        Self::check_threshold_reachable(&env)?;
 
         // Emit event
-        env.events().publish(
-            (EVENT_TAG, symbol_short!("complete")),
-            recovery_request.new_passkey.id
-        );
+        env.events().publish((EVENT_TAG, symbol_short!("sig_rm")), signer);
 
         Ok(())
     }
 
-    /// Get current daily spending
-    pub fn get_daily_spending(env: Env) -> i128 {
-        let today = env.ledger().timestamp() / (24 * 60 * 60);
+    /// Restrict what a signer may authorize, None lifts all restrictions
+    pub fn set_permissions(
+        env: Env,
+        signer: Signer,
+        permissions: Option<SignerPermissions>
+    ) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
 
-        if
-            let Some(spending) = env
-                .storage()
-                .instance()
-                .get::<DataKey, DailySpending>(&DataKey::DailySpending)
-        {
-            if spending.date == today {
-                return spending.amount;
-            }
+        if !Self::list_signers(env.clone())?.contains(&signer) {
+            return Err(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND));
+        }
+
+        let key = DataKey::Permissions(signer.clone());
+
+        match &permissions {
+            Some(permissions) => env.storage().instance().set(&key, permissions),
+            None => env.storage().instance().remove(&key),
         }
 
-        0
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("perms")), (signer, permissions));
+
+        Ok(())
     }
 
-    /// Get transaction history (last 50 transactions)
-    pub fn get_transaction_history(env: Env) -> Vec<Transaction> {
-        env.storage().instance().get(&DataKey::TransactionHistory).unwrap_or(Vec::new(&env))
+    /// Get the permission set of a signer, None when it is unrestricted
+    pub fn get_permissions(env: Env, signer: Signer) -> Option<SignerPermissions> {
+        env.storage().instance().get(&DataKey::Permissions(signer))
     }
 
-    /// WebAuthn signature verification
-    pub fn __check_auth(env: Env, signature: WebAuthnSignature) -> Result<(), SdkError> {
-        // Get current passkey
-        let passkey: PasskeyCredential = env
-            .storage()
-            .instance()
-            .get(&DataKey::Passkey)
-            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))?;
+    /// Add a session key that can call `functions` on the wallet until the `expires_at` ledger,
+    /// spending at most `budgets` of each token in total
+    pub fn add_session_key(
+        env: Env,
+        public_key: BytesN<32>,
+        expires_at: u32,
+        budgets: Map<Address, i128>,
+        functions: Vec<Symbol>
+    ) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
 
-        // 1. Verify the signature against the public key
-        // Create the client data hash (SHA-256 of the client_data_json)
-        let client_data_hash = env.crypto().sha256(&signature.client_data_json);
+        if expires_at <= env.ledger().sequence() {
+            return Err(SdkError::from_contract_error(ERROR_SESSION_EXPIRED));
+        }
 
-        // Create the message that was signed (concatenate authenticator_data and client_data_hash)
-        let mut message = Bytes::new(&env);
-        message.append(&signature.authenticator_data);
-        message.extend_from_array(&client_data_hash.to_array());
+        for budget in budgets.values().iter() {
+            if budget < 0 {
+                return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+            }
+        }
 
-        // Hash the message to get the final verification data
-        let verification_data = env.crypto().sha256(&message);
+        // Only payments, a session key must never reach settings or signer management
+        let payment_functions = [
+            symbol_short!("send"),
+            symbol_short!("withdraw"),
+            Symbol::new(&env, "send_with_fee"),
+        ];
 
-        // Verify the signature using secp256r1 (P-256)
-        env.crypto().secp256r1_verify(
-            &passkey.public_key,
-            &verification_data,
-            &signature.signature
-        );
+        for function in functions.iter() {
+            if !payment_functions.contains(&function) {
+                return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+            }
+        }
 
-        // 2. Extract and verify the challenge from client_data_json
-        // In a real implementation, we would properly parse the JSON
-        // For now, we'll do a basic check that the client_data_json contains data
-        if signature.client_data_json.len() == 0 {
-            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        // Re-adding would silently restore spent budgets
+        let key = DataKey::SessionKey(public_key.clone());
+
+        if env.storage().instance().has(&key) {
+            return Err(SdkError::from_contract_error(ERROR_SESSION_EXISTS));
         }
 
-        // 3. Verify the authenticator_data
+        let session_key = SessionKey {
+            public_key: public_key.clone(),
+            expires_at,
+            budgets,
+            functions,
+        };
+
+        env.storage().instance().set(&key, &session_key);
+
+        let mut session_keys = Self::get_session_keys(&env);
+        session_keys.push_back(public_key.clone());
+        env.storage().instance().set(&DataKey::SessionKeys, &session_keys);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("sess_add")), (public_key, expires_at));
+
+        Ok(())
+    }
+
+    /// Revoke a session key before it expires
+    pub fn revoke_session_key(env: Env, public_key: BytesN<32>) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let key = DataKey::SessionKey(public_key.clone());
+
+        if !env.storage().instance().has(&key) {
+            return Err(SdkError::from_contract_error(ERROR_SESSION_NOT_FOUND));
+        }
+
+        env.storage().instance().remove(&key);
+
+        let mut session_keys = Self::get_session_keys(&env);
+        if let Some(index) = session_keys.first_index_of(&public_key) {
+            session_keys.remove(index);
+        }
+        env.storage().instance().set(&DataKey::SessionKeys, &session_keys);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("sess_rm")), public_key);
+
+        Ok(())
+    }
+
+    /// Get a session key with its remaining budgets
+    pub fn get_session_key(env: Env, public_key: BytesN<32>) -> Option<SessionKey> {
+        env.storage().instance().get(&DataKey::SessionKey(public_key))
+    }
+
+    /// Get every signer of the wallet, passkeys first
+    pub fn list_signers(env: Env) -> Result<Vec<Signer>, SdkError> {
+        let mut signers = Vec::<Signer>::new(&env);
+
+        for passkey_id in Self::get_passkeys(&env)?.keys().iter() {
+            signers.push_back(Signer::Passkey(passkey_id));
+        }
+
+        signers.append(&Self::get_signers(&env));
+
+        Ok(signers)
+    }
+
+    /// Attach a policy contract that is consulted on every authorization
+    pub fn add_policy(env: Env, policy: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let user_manager: Address = env
+            .storage()
+            .instance()
+            .get(&DataKey::UserManager)
+            .ok_or(SdkError::from_contract_error(ERROR_POLICY_NOT_APPROVED))?;
+
+        if !UserManagerClient::new(&env, &user_manager).is_policy_approved(&policy) {
+            return Err(SdkError::from_contract_error(ERROR_POLICY_NOT_APPROVED));
+        }
+
+        let mut policies = Self::get_policies(&env);
+
+        if policies.contains(&policy) {
+            return Err(SdkError::from_contract_error(ERROR_POLICY_EXISTS));
+        }
+
+        policies.push_back(policy.clone());
+        env.storage().instance().set(&DataKey::Policies, &policies);
+
+        // A removal of an earlier attachment that took effect is done
+        let mut removals = Self::policy_removals(&env);
+        removals.remove(policy.clone());
+        env.storage().instance().set(&DataKey::PolicyRemovals, &removals);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pol_add")), policy);
+
+        Ok(())
+    }
+
+    /// Detach a policy contract once the limit delay has passed. Policies aren't consulted on
+    /// this call, so a policy that rejects everything can't lock the wallet.
+    pub fn remove_policy(env: Env, policy: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if !Self::get_policies(&env).contains(&policy) {
+            return Err(SdkError::from_contract_error(ERROR_POLICY_NOT_FOUND));
+        }
+
+        let effective_at = env.ledger().timestamp() + Self::limit_delay(&env)?;
+
+        let mut removals = Self::policy_removals(&env);
+        removals.set(policy.clone(), effective_at);
+        env.storage().instance().set(&DataKey::PolicyRemovals, &removals);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pol_rm")), (policy, effective_at));
+
+        Ok(())
+    }
+
+    /// Cancel a queued policy removal
+    pub fn cancel_policy_removal(env: Env, policy: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let mut removals = Self::policy_removals(&env);
+        let effective_at = removals
+            .get(policy.clone())
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        // A removal whose delay already passed is done
+        if effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        removals.remove(policy.clone());
+        env.storage().instance().set(&DataKey::PolicyRemovals, &removals);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pol_cncl")), policy);
+
+        Ok(())
+    }
+
+    /// Get the policy contracts attached to the wallet
+    pub fn list_policies(env: Env) -> Vec<Address> {
+        Self::get_policies(&env)
+    }
+
+    /// Get the queued policy removals with the time each takes effect
+    pub fn get_policy_removals(env: Env) -> Map<Address, u64> {
+        let now = env.ledger().timestamp();
+        let mut removals = Map::new(&env);
+
+        for (policy, effective_at) in Self::policy_removals(&env).iter() {
+            if effective_at > now {
+                removals.set(policy, effective_at);
+            }
+        }
+
+        removals
+    }
+
+    /// Add a recipient to the address book. It becomes trusted once the limit delay has passed,
+    /// an empty `limits` map means sends to it aren't limited at all.
+    pub fn add_contact(
+        env: Env,
+        address: Address,
+        nickname_hash: BytesN<32>,
+        limits: Map<Address, i128>
+    ) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if limits.values().iter().any(|limit| limit < 0) {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+        }
+
+        let mut contacts = Self::get_contacts(&env);
+
+        if contacts.contains_key(address.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_CONTACT_EXISTS));
+        }
+
+        // Trusting a contact relaxes the limits, so it waits as long as a limit increase
+        let now = env.ledger().timestamp();
+        let contact = Contact {
+            address: address.clone(),
+            nickname_hash,
+            limits,
+            added_at: now,
+            trusted_at: now + Self::limit_delay(&env)?,
+        };
+
+        contacts.set(address.clone(), contact.clone());
+        env.storage().instance().set(&DataKey::Contacts, &contacts);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("cont_add")), (address, contact.trusted_at));
+
+        Ok(())
+    }
+
+    /// Remove a recipient from the address book
+    pub fn remove_contact(env: Env, address: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let mut contacts = Self::get_contacts(&env);
+
+        if !contacts.contains_key(address.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_CONTACT_NOT_FOUND));
+        }
+
+        contacts.remove(address.clone());
+        env.storage().instance().set(&DataKey::Contacts, &contacts);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("cont_rm")), address);
+
+        Ok(())
+    }
+
+    /// Get an address book entry
+    pub fn get_contact(env: Env, address: Address) -> Result<Contact, SdkError> {
+        Self::get_contacts(&env)
+            .get(address)
+            .ok_or(SdkError::from_contract_error(ERROR_CONTACT_NOT_FOUND))
+    }
+
+    /// Get the whole address book
+    pub fn list_contacts(env: Env) -> Vec<Contact> {
+        Self::get_contacts(&env).values()
+    }
+
+    /// Allow passkey assertions made from a new WebAuthn origin (e.g. "https://app.numberspay.com")
+    pub fn add_origin(env: Env, origin: Bytes) -> Result<(), SdkError> {
+        // Require authentication with current passkey
+        env.current_contract_address().require_auth();
+
+        let mut settings = Self::get_settings(&env)?;
+
+        if !settings.allowed_origins.contains(&origin) {
+            settings.allowed_origins.push_back(origin.clone());
+            env.storage().instance().set(&DataKey::Settings, &settings);
+        }
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("orig_add")), origin);
+
+        Ok(())
+    }
+
+    /// Stop accepting passkey assertions made from a WebAuthn origin
+    pub fn remove_origin(env: Env, origin: Bytes) -> Result<(), SdkError> {
+        // Require authentication with current passkey
+        env.current_contract_address().require_auth();
+
+        let mut settings = Self::get_settings(&env)?;
+
+        let index = settings.allowed_origins
+            .first_index_of(&origin)
+            .ok_or(SdkError::from_contract_error(ERROR_ORIGIN_NOT_ALLOWED))?;
+
+        // Removing the last origin would lock the wallet
+        if settings.allowed_origins.len() == 1 {
+            return Err(SdkError::from_contract_error(ERROR_NO_ORIGINS));
+        }
+
+        settings.allowed_origins.remove(index);
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("orig_rm")), origin);
+
+        Ok(())
+    }
+
+    /// Set when passkey assertions must be user verified (biometric / PIN) rather than just present
+    pub fn set_user_verification(env: Env, policy: UserVerification) -> Result<(), SdkError> {
+        // Require authentication with current passkey
+        env.current_contract_address().require_auth();
+
+        if let UserVerification::AboveAmount(threshold) = policy {
+            if threshold < 0 {
+                return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+            }
+        }
+
+        let mut settings = Self::get_settings(&env)?;
+        settings.user_verification = policy.clone();
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("uv_policy")), policy);
+
+        Ok(())
+    }
+
+    /// Track daily limits over a rolling 24 hours or per calendar day. Switching to rolling
+    /// applies right away, anything else waits for the limit delay.
+    pub fn set_spending_window(env: Env, window: SpendingWindow) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if let SpendingWindow::Calendar(utc_offset) = window {
+            if utc_offset.abs() > MAX_UTC_OFFSET {
+                return Err(SdkError::from_contract_error(ERROR_INVALID_UTC_OFFSET));
+            }
+        }
+
+        let current = Self::spending_window(&env)?;
+        env.storage().instance().remove(&DataKey::PendingSpendingWindow);
+
+        let mut settings = Self::get_settings(&env)?;
+
+        // The last 24 hours cover any calendar day, only switching to rolling can't shorten it
+        if window == SpendingWindow::Rolling || window == current {
+            settings.spending_window = window.clone();
+            env.storage().instance().set(&DataKey::Settings, &settings);
+
+            // Emit event
+            env.events().publish((EVENT_TAG, symbol_short!("window")), window);
+
+            return Ok(());
+        }
+
+        settings.spending_window = current;
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        let pending = PendingSpendingWindow {
+            window,
+            effective_at: env.ledger().timestamp() + Self::limit_delay(&env)?,
+        };
+        env.storage().instance().set(&DataKey::PendingSpendingWindow, &pending);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("window_q")), pending);
+
+        Ok(())
+    }
+
+    /// Cancel a queued spending window change
+    pub fn cancel_spending_window(env: Env) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let pending: PendingSpendingWindow = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingSpendingWindow)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        if pending.effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        env.storage().instance().remove(&DataKey::PendingSpendingWindow);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("window_cn")), pending.window);
+
+        Ok(())
+    }
+
+    /// Get the spending window currently in force
+    pub fn get_spending_window(env: Env) -> Result<SpendingWindow, SdkError> {
+        Self::spending_window(&env)
+    }
+
+    /// Get a queued spending window change
+    pub fn get_pending_spending_window(env: Env) -> Option<PendingSpendingWindow> {
+        env.storage().instance().get(&DataKey::PendingSpendingWindow)
+    }
+
+    /// Cap how many payments can go out per hour and day and how much new recipients can get
+    pub fn set_velocity_rules(env: Env, rules: VelocityRules) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if rules.new_recipient_limits.values().iter().any(|limit| limit < 0) {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+        }
+
+        // Any queued change is replaced, one that already took effect is the current rules
+        let current = Self::velocity_rules(&env)?;
+        env.storage().instance().remove(&DataKey::PendingVelocity);
+
+        // Tighter caps apply right away. A token limit that is dropped stays until the delay
+        let mut new_recipient_limits = current.new_recipient_limits.clone();
+        for (token, limit) in rules.new_recipient_limits.iter() {
+            let limit = new_recipient_limits
+                .get(token.clone())
+                .map_or(limit, |current| current.min(limit));
+            new_recipient_limits.set(token, limit);
+        }
+
+        let tightened = VelocityRules {
+            max_per_hour: Self::lower_cap(current.max_per_hour, rules.max_per_hour),
+            max_per_day: Self::lower_cap(current.max_per_day, rules.max_per_day),
+            new_recipient_period: current.new_recipient_period.max(rules.new_recipient_period),
+            new_recipient_limits,
+        };
+
+        let mut settings = Self::get_settings(&env)?;
+        settings.velocity = tightened.clone();
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        if tightened == rules {
+            // Emit event
+            env.events().publish((EVENT_TAG, symbol_short!("velocity")), rules);
+
+            return Ok(());
+        }
+
+        let pending = PendingVelocity {
+            rules,
+            effective_at: env.ledger().timestamp() + Self::limit_delay(&env)?,
+        };
+        env.storage().instance().set(&DataKey::PendingVelocity, &pending);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("velo_q")), pending);
+
+        Ok(())
+    }
+
+    /// Cancel a queued velocity rules change
+    pub fn cancel_velocity_rules(env: Env) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let pending: PendingVelocity = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingVelocity)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        if pending.effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        env.storage().instance().remove(&DataKey::PendingVelocity);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("velo_cn")), pending.rules);
+
+        Ok(())
+    }
+
+    /// Get the velocity rules currently in force
+    pub fn get_velocity_rules(env: Env) -> Result<VelocityRules, SdkError> {
+        Self::velocity_rules(&env)
+    }
+
+    /// Get a queued velocity rules change
+    pub fn get_pending_velocity_rules(env: Env) -> Option<PendingVelocity> {
+        env.storage().instance().get(&DataKey::PendingVelocity)
+    }
+
+    /// Get what the wallet remembers about a recipient, None if it was never paid
+    pub fn get_recipient(env: Env, recipient: Address) -> Option<RecipientRecord> {
+        env.storage().persistent().get(&DataKey::Recipient(recipient))
+    }
+
+    /// Hold transfers above a per token threshold until a second passkey or a guardian approves
+    pub fn set_step_up(env: Env, policy: StepUpPolicy) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if policy.thresholds.values().iter().any(|threshold| threshold < 0) {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
+        }
+
+        // Any queued change is replaced, one that already took effect is the current policy
+        let current = Self::step_up_policy(&env)?;
+        env.storage().instance().remove(&DataKey::PendingStepUp);
+
+        // Lower thresholds and a shorter window apply right away. A dropped threshold stays
+        // until the delay
+        let mut thresholds = current.thresholds.clone();
+        for (token, threshold) in policy.thresholds.iter() {
+            let threshold = thresholds
+                .get(token.clone())
+                .map_or(threshold, |current| current.min(threshold));
+            thresholds.set(token, threshold);
+        }
+
+        let tightened = StepUpPolicy {
+            thresholds,
+            approval_window: current.approval_window.min(policy.approval_window),
+        };
+
+        let mut settings = Self::get_settings(&env)?;
+        settings.step_up = tightened.clone();
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        if tightened == policy {
+            // Emit event
+            env.events().publish((EVENT_TAG, symbol_short!("step_up")), policy);
+
+            return Ok(());
+        }
+
+        let pending = PendingStepUp {
+            policy,
+            effective_at: env.ledger().timestamp() + Self::limit_delay(&env)?,
+        };
+        env.storage().instance().set(&DataKey::PendingStepUp, &pending);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("stepup_q")), pending);
+
+        Ok(())
+    }
+
+    /// Cancel a queued step-up policy change
+    pub fn cancel_step_up(env: Env) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let pending: PendingStepUp = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingStepUp)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        if pending.effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        env.storage().instance().remove(&DataKey::PendingStepUp);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("stepup_cn")), pending.policy);
+
+        Ok(())
+    }
+
+    /// Get the step-up policy currently in force
+    pub fn get_step_up(env: Env) -> Result<StepUpPolicy, SdkError> {
+        Self::step_up_policy(&env)
+    }
+
+    /// Get a queued step-up policy change
+    pub fn get_pending_step_up(env: Env) -> Option<PendingStepUp> {
+        env.storage().instance().get(&DataKey::PendingStepUp)
+    }
+
+    /// Add a guardian, e.g. a family member's account, to approve step-up transfers and recovery
+    pub fn add_guardian(env: Env, guardian: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let mut guardians = Self::get_guardians(&env);
+
+        if guardians.contains_key(guardian.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_GUARDIAN_EXISTS));
+        }
+
+        guardians.set(guardian.clone(), env.ledger().timestamp());
+        env.storage().instance().set(&DataKey::Guardians, &guardians);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("grd_add")), guardian);
+
+        Ok(())
+    }
+
+    /// Remove a guardian
+    pub fn remove_guardian(env: Env, guardian: Address) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let mut guardians = Self::get_guardians(&env);
+
+        if !guardians.contains_key(guardian.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND));
+        }
+
+        // Recovery has to stay possible, lower the threshold first
+        if guardians.len() - 1 < Self::get_settings(&env)?.recovery_threshold {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_THRESHOLD));
+        }
+
+        guardians.remove(guardian.clone());
+        env.storage().instance().set(&DataKey::Guardians, &guardians);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("grd_rm")), guardian);
+
+        Ok(())
+    }
+
+    /// Get the wallet's guardians
+    pub fn list_guardians(env: Env) -> Vec<Address> {
+        Self::get_guardians(&env).keys()
+    }
+
+    /// Approve and execute a pending transfer before its deadline
+    pub fn approve_pending(env: Env, id: u32, approval: StepUpApproval) -> Result<(), SdkError> {
+        let mut pending_transfers = Self::get_pending_transfers(&env);
+        let pending = pending_transfers
+            .get(id)
+            .ok_or(SdkError::from_contract_error(ERROR_PENDING_NOT_FOUND))?;
+
+        if env.ledger().timestamp() > pending.deadline {
+            return Err(SdkError::from_contract_error(ERROR_PENDING_EXPIRED));
+        }
+
+        // The approver has to predate the transfer by the limit delay
+        let approvers_added_by = pending.created_at.saturating_sub(Self::limit_delay(&env)?);
+
+        match approval {
+            StepUpApproval::Passkey(signature) => {
+                if pending.initiators.contains(Signer::Passkey(signature.id.clone())) {
+                    return Err(SdkError::from_contract_error(ERROR_SELF_APPROVAL));
+                }
+
+                let passkey = Self::get_passkeys(&env)?
+                    .get(signature.id.clone())
+                    .ok_or(SdkError::from_contract_error(ERROR_PASSKEY_NOT_FOUND))?;
+
+                if passkey.created_at > approvers_added_by {
+                    return Err(SdkError::from_contract_error(ERROR_APPROVER_TOO_NEW));
+                }
+
+                // The approval releases the transfer, so it is checked as a send of the held
+                // amount against the approver's permissions and the policies, and always needs UV
+                let signer = Signer::Passkey(signature.id.clone());
+                let contexts = Vec::from_array(
+                    &env,
+                    [
+                        Context::Contract(ContractContext {
+                            contract: env.current_contract_address(),
+                            fn_name: symbol_short!("send"),
+                            args: (
+                                pending.to.clone(),
+                                pending.token.clone(),
+                                pending.amount,
+                            ).into_val(&env),
+                        }),
+                    ]
+                );
+
+                let payload = (symbol_short!("approve"), env.current_contract_address(), id);
+                let payload_hash = env.crypto().sha256(&payload.to_xdr(&env));
+                Self::verify_passkey(
+                    &env,
+                    &payload_hash,
+                    signature,
+                    &contexts,
+                    &UserVerification::Always
+                )?;
+                Self::check_permissions(&env, &signer, &contexts)?;
+                Self::check_policies(&env, &Vec::from_array(&env, [signer]), &contexts)?;
+            }
+            StepUpApproval::Guardian(guardian) => {
+                let added_at = Self::get_guardians(&env)
+                    .get(guardian.clone())
+                    .ok_or(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND))?;
+
+                if added_at > approvers_added_by {
+                    return Err(SdkError::from_contract_error(ERROR_APPROVER_TOO_NEW));
+                }
+
+                guardian.require_auth();
+            }
+        }
+
+        pending_transfers.remove(id);
+        env.storage().instance().set(&DataKey::PendingTransfers, &pending_transfers);
+
+        // Check balance
+        let wallet_address = env.current_contract_address();
+        let token_client = token::Client::new(&env, &pending.token);
+
+        if token_client.balance(&wallet_address) < pending.amount {
+            return Err(SdkError::from_contract_error(ERROR_INSUFFICIENT_BALANCE));
+        }
+
+        // Transfer tokens
+        token_client.transfer(&wallet_address, &pending.to, &pending.amount);
+
+        // The reservation ends with the pending transfer, the amount is now spent
+        let value = Self::base_value(&env, &pending.token, pending.amount)?;
+        Self::update_daily_spending(&env, &pending.token, pending.amount, value)?;
+
+        // Record transaction
+        Self::record_transaction(
+            &env,
+            wallet_address,
+            pending.to.clone(),
+            pending.token.clone(),
+            pending.amount
+        )?;
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("pend_ok")),
+            (id, pending.to, pending.token, pending.amount)
+        );
+
+        Ok(())
+    }
+
+    /// Reject a pending transfer, by the wallet's signers or a guardian
+    pub fn reject_pending(env: Env, id: u32, guardian: Option<Address>) -> Result<(), SdkError> {
+        match guardian {
+            Some(guardian) => {
+                if !Self::get_guardians(&env).contains_key(guardian.clone()) {
+                    return Err(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND));
+                }
+
+                guardian.require_auth();
+            }
+            None => {
+                // Require authentication with a current signer
+                env.current_contract_address().require_auth();
+            }
+        }
+
+        let mut pending_transfers = Self::get_pending_transfers(&env);
+
+        if !pending_transfers.contains_key(id) {
+            return Err(SdkError::from_contract_error(ERROR_PENDING_NOT_FOUND));
+        }
+
+        pending_transfers.remove(id);
+        env.storage().instance().set(&DataKey::PendingTransfers, &pending_transfers);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("pend_rej")), id);
+
+        Ok(())
+    }
+
+    /// Get the transfers waiting for approval, expired ones excluded
+    pub fn list_pending(env: Env) -> Vec<PendingTransfer> {
+        let now = env.ledger().timestamp();
+        let mut pending = Vec::<PendingTransfer>::new(&env);
+
+        for transfer in Self::get_pending_transfers(&env).values().iter() {
+            if transfer.deadline >= now {
+                pending.push_back(transfer);
+            }
+        }
+
+        pending
+    }
+
+    /// Require signatures from several distinct signers, per function if needed
+    pub fn set_threshold(env: Env, policy: ThresholdPolicy) -> Result<(), SdkError> {
+        // Require authentication with the current threshold
+        env.current_contract_address().require_auth();
+
+        let mut settings = Self::get_settings(&env)?;
+        settings.threshold = policy.clone();
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        Self::check_threshold_reachable(&env)?;
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("threshold")), policy);
+
+        Ok(())
+    }
+
+    /// Get the WebAuthn relying party ID the wallet's passkeys are scoped to
+    pub fn get_rp_id(env: Env) -> Result<Bytes, SdkError> {
+        Ok(Self::get_settings(&env)?.rp_id)
+    }
+
+    /// Get the WebAuthn origins passkey assertions are accepted from
+    pub fn get_origins(env: Env) -> Result<Vec<Bytes>, SdkError> {
+        Ok(Self::get_settings(&env)?.allowed_origins)
+    }
+
+    /// Set how many guardians have to approve a recovery, 0 turns recovery off
+    pub fn set_recovery_threshold(env: Env, threshold: u32) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        if threshold > Self::get_guardians(&env).len() {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_THRESHOLD));
+        }
+
+        let mut settings = Self::get_settings(&env)?;
+        settings.recovery_threshold = threshold;
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("reco_thr")), threshold);
+
+        Ok(())
+    }
+
+    /// Initiate recovery process, as one of the guardians
+    pub fn initiate_recovery(
+        env: Env,
+        guardian: Address,
+        new_passkey_id: Bytes,
+        new_public_key: Bytes
+    ) -> Result<(), SdkError> {
+        let settings = Self::get_settings(&env)?;
+
+        if settings.recovery_threshold == 0 {
+            return Err(SdkError::from_contract_error(ERROR_UNAUTHORIZED));
+        }
+
+        if !Self::get_guardians(&env).contains_key(guardian.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND));
+        }
+
+        guardian.require_auth();
+
+        // One request at a time, the wallet's signers can cancel a bad one
+        if env.storage().instance().has(&DataKey::Recovery) {
+            return Err(SdkError::from_contract_error(ERROR_RECOVERY_PENDING));
+        }
+
+        let new_passkey = PasskeyCredential {
+            id: new_passkey_id.clone(),
+            public_key: Self::parse_public_key(&env, &new_public_key)?,
+            created_at: env.ledger().timestamp(),
+            sign_count: 0,
+        };
+
+        let mut recovery_request = RecoveryRequest {
+            new_passkey: new_passkey.clone(),
+            requested_at: env.ledger().timestamp(),
+            approvals: Vec::from_array(&env, [guardian]),
+            executable_at: None,
+        };
+
+        // A 1-of-N setup starts the time-lock right away
+        if settings.recovery_threshold == 1 {
+            recovery_request.executable_at = Some(env.ledger().timestamp() + RECOVERY_DELAY);
+        }
+
+        env.storage().instance().set(&DataKey::Recovery, &recovery_request);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("reco_init")), new_passkey_id);
+
+        Ok(())
+    }
+
+    /// Approve the pending recovery as another guardian, the time-lock starts at the threshold
+    pub fn approve_recovery(env: Env, guardian: Address) -> Result<(), SdkError> {
+        let mut recovery_request = Self::get_recovery_request(&env)?;
+
+        if !Self::get_guardians(&env).contains_key(guardian.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND));
+        }
+
+        guardian.require_auth();
+
+        if recovery_request.approvals.contains(&guardian) {
+            return Err(SdkError::from_contract_error(ERROR_ALREADY_APPROVED));
+        }
+
+        recovery_request.approvals.push_back(guardian.clone());
+
+        let threshold = Self::get_settings(&env)?.recovery_threshold;
+
+        if
+            recovery_request.executable_at.is_none() &&
+            Self::recovery_approvals(&env, &recovery_request) >= threshold
+        {
+            recovery_request.executable_at = Some(env.ledger().timestamp() + RECOVERY_DELAY);
+        }
+
+        env.storage().instance().set(&DataKey::Recovery, &recovery_request);
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("reco_appr")),
+            (guardian, recovery_request.executable_at)
+        );
+
+        Ok(())
+    }
+
+    /// Cancel the pending recovery, e.g. when the wallet's passkeys aren't lost after all
+    pub fn cancel_recovery(env: Env) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let recovery_request = Self::get_recovery_request(&env)?;
+        env.storage().instance().remove(&DataKey::Recovery);
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("reco_cncl")),
+            recovery_request.new_passkey.id
+        );
+
+        Ok(())
+    }
+
+    /// Get the pending recovery request, if any
+    pub fn get_recovery(env: Env) -> Option<RecoveryRequest> {
+        env.storage().instance().get(&DataKey::Recovery)
+    }
+
+    /// Complete recovery process once the guardians approved it and the time-lock passed.
+    /// Signers, session keys, policies and contacts are cleared. Guardians stay, they're the ones
+    /// who just recovered the wallet and the new passkey can review them with `list_guardians`.
+    pub fn complete_recovery(env: Env) -> Result<(), SdkError> {
+        let recovery_request = Self::get_recovery_request(&env)?;
+
+        let executable_at = recovery_request.executable_at.ok_or(
+            SdkError::from_contract_error(ERROR_THRESHOLD_NOT_MET)
+        )?;
+
+        if env.ledger().timestamp() < executable_at {
+            return Err(SdkError::from_contract_error(ERROR_RECOVERY_PENDING));
+        }
+
+        // Guardians removed since they approved no longer count
+        let threshold = Self::get_settings(&env)?.recovery_threshold;
+
+        if threshold == 0 || Self::recovery_approvals(&env, &recovery_request) < threshold {
+            return Err(SdkError::from_contract_error(ERROR_THRESHOLD_NOT_MET));
+        }
+
+        // Anything that could sign next to the lost devices goes too, whoever holds them may
+        // have added signers, session keys, policies or contacts to skip the limits
+        for signer in Self::list_signers(env.clone())?.iter() {
+            env.storage().instance().remove(&DataKey::Permissions(signer));
+        }
+
+        let new_signer = Signer::Passkey(recovery_request.new_passkey.id.clone());
+        env.storage().instance().remove(&DataKey::Permissions(new_signer));
+        env.storage().instance().set(&DataKey::Signers, &Vec::<Signer>::new(&env));
+
+        for public_key in Self::get_session_keys(&env).iter() {
+            env.storage().instance().remove(&DataKey::SessionKey(public_key));
+        }
+        env.storage().instance().remove(&DataKey::SessionKeys);
+
+        env.storage().instance().set(&DataKey::Policies, &Vec::<Address>::new(&env));
+        env.storage().instance().remove(&DataKey::PolicyRemovals);
+        env.storage().instance().remove(&DataKey::Contacts);
+
+        // The recovered passkey is the only signer left, so it has to be enough on its own
+        let mut settings = Self::get_settings(&env)?;
+        settings.threshold = ThresholdPolicy {
+            threshold: 1,
+            functions: Map::new(&env),
+        };
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        // Replace all passkeys with the recovered one, the old devices are presumed lost
+        let mut passkeys = Map::<Bytes, PasskeyCredential>::new(&env);
+        passkeys.set(recovery_request.new_passkey.id.clone(), recovery_request.new_passkey.clone());
+        env.storage().instance().set(&DataKey::Passkeys, &passkeys);
+
+        // Remove recovery request
+        env.storage().instance().remove(&DataKey::Recovery);
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("complete")),
+            recovery_request.new_passkey.id
+        );
+
+        Ok(())
+    }
+
+    /// Check a wallet passkey signed `hash` (e.g. a login challenge) without a transaction, using
+    /// the same WebAuthn checks as `__check_auth`. The challenge must be the base64url encoded
+    /// sha256("nbswallet:login" || hash), so a login assertion never authorizes a transaction
+    /// and a transaction assertion never passes as a login.
+    pub fn verify_message(env: Env, hash: BytesN<32>, signature: WebAuthnSignature) -> bool {
+        let passkey = match Self::get_passkeys(&env) {
+            Ok(passkeys) => passkeys.get(signature.id.clone()),
+            Err(_) => None,
+        };
+
+        let Some(passkey) = passkey else {
+            return false;
+        };
+
+        let user_verification = match Self::get_settings(&env) {
+            Ok(settings) => settings.user_verification,
+            Err(_) => {
+                return false;
+            }
+        };
+
+        let mut message = Bytes::from_slice(&env, LOGIN_DOMAIN);
+        message.extend_from_array(&hash.to_array());
+        let challenge = env.crypto().sha256(&message);
+
+        // Checked in contract code, the host's secp256r1_verify traps on a bad signature
+        match Self::signed_digest(&env, &signature) {
+            Ok((digest, compact_signature)) => {
+                if
+                    !secp256r1::verify(
+                        &passkey.public_key.to_array(),
+                        &digest.to_array(),
+                        &compact_signature
+                    )
+                {
+                    return false;
+                }
+            }
+            Err(_) => {
+                return false;
+            }
+        }
+
+        Self::verify_assertion(
+            &env,
+            &challenge.to_array(),
+            &signature,
+            &passkey,
+            &Vec::new(&env),
+            &user_verification
+        ).is_ok()
+    }
+
+    /// Get how much of a token was spent in the current window and how much can still be spent
+    pub fn get_daily_spending(env: Env, token: Address) -> Result<SpendingStatus, SdkError> {
+        let limits = Self::get_limits(&env, &token)?;
+        let spent = Self::spent_in_window(&env, &DataKey::DailySpending(token))?;
+
+        Ok(Self::spending_status(LimitWindow::Daily, spent, limits.daily))
+    }
+
+    /// Get the spending and remaining allowance of a token for every configured window
+    pub fn get_limits_status(env: Env, token: Address) -> Result<Vec<SpendingStatus>, SdkError> {
+        let limits = Self::get_limits(&env, &token)?;
+        Self::limits_status(&env, &DataKey::DailySpending(token), &limits)
+    }
+
+    /// Change the limits of a token or the base currency limits. Lower limits apply right away,
+    /// higher ones are queued until the limit delay has passed and can be cancelled until then.
+    /// Base currency limits are valued through the UserManager's oracle, so they need one.
+    pub fn set_limits(env: Env, scope: LimitScope, limits: SpendingLimits) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        Self::check_limits_valid(&limits)?;
+
+        if scope == LimitScope::Base && !env.storage().instance().has(&DataKey::UserManager) {
+            return Err(SdkError::from_contract_error(ERROR_NO_PRICE_SOURCE));
+        }
+
+        // Any queued change is replaced, one that already took effect is the current limits
+        let current = Self::scope_limits(&env, &scope)?;
+        env.storage().instance().remove(&DataKey::PendingLimits(scope.clone()));
+
+        // Without base limits there is no cap, so any base limits are a decrease
+        let lowered = match current {
+            Some(current) =>
+                SpendingLimits {
+                    daily: current.daily.min(limits.daily),
+                    weekly: Self::lower_cap(current.weekly, limits.weekly),
+                    monthly: Self::lower_cap(current.monthly, limits.monthly),
+                },
+            None => limits.clone(),
+        };
+
+        Self::store_limits(&env, &scope, &lowered)?;
+
+        if lowered == limits {
+            // Emit event
+            env.events().publish((EVENT_TAG, symbol_short!("lim_set")), (scope, limits));
+
+            return Ok(());
+        }
+
+        let pending = PendingLimits {
+            limits,
+            effective_at: env.ledger().timestamp() + Self::limit_delay(&env)?,
+        };
+        env.storage().instance().set(&DataKey::PendingLimits(scope.clone()), &pending);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("lim_queue")), (scope, pending));
+
+        Ok(())
+    }
+
+    /// Cancel a queued limit increase
+    pub fn cancel_limits(env: Env, scope: LimitScope) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let key = DataKey::PendingLimits(scope.clone());
+        let pending: PendingLimits = env
+            .storage()
+            .instance()
+            .get(&key)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        // An increase whose delay already passed is in force, cancelling it is a decrease
+        if pending.effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        env.storage().instance().remove(&key);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("lim_cncl")), scope);
+
+        Ok(())
+    }
+
+    /// Get a queued limit increase
+    pub fn get_pending_limits(env: Env, scope: LimitScope) -> Option<PendingLimits> {
+        env.storage().instance().get(&DataKey::PendingLimits(scope))
+    }
+
+    /// Set how long limit increases wait before taking effect. A longer delay applies right
+    /// away, a shorter one only after the current delay.
+    pub fn set_limit_delay(env: Env, delay: u64) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let current = Self::limit_delay(&env)?;
+        env.storage().instance().remove(&DataKey::PendingLimitDelay);
+
+        let mut settings = Self::get_settings(&env)?;
+
+        if delay >= current {
+            settings.limit_delay = delay;
+            env.storage().instance().set(&DataKey::Settings, &settings);
+
+            // Emit event
+            env.events().publish((EVENT_TAG, symbol_short!("delay_set")), delay);
+
+            return Ok(());
+        }
+
+        // Keep a shorter delay that already took effect, then queue the new one behind it
+        settings.limit_delay = current;
+        env.storage().instance().set(&DataKey::Settings, &settings);
+
+        let pending = PendingLimitDelay {
+            delay,
+            effective_at: env.ledger().timestamp() + current,
+        };
+        env.storage().instance().set(&DataKey::PendingLimitDelay, &pending);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("delay_que")), pending);
+
+        Ok(())
+    }
+
+    /// Cancel a queued shorter limit delay
+    pub fn cancel_limit_delay(env: Env) -> Result<(), SdkError> {
+        // Require authentication with a current signer
+        env.current_contract_address().require_auth();
+
+        let pending: PendingLimitDelay = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingLimitDelay)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE))?;
+
+        if pending.effective_at <= env.ledger().timestamp() {
+            return Err(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE));
+        }
+
+        env.storage().instance().remove(&DataKey::PendingLimitDelay);
+
+        // Emit event
+        env.events().publish((EVENT_TAG, symbol_short!("delay_cnl")), pending.delay);
+
+        Ok(())
+    }
+
+    /// Get the delay limit increases currently wait
+    pub fn get_limit_delay(env: Env) -> Result<u64, SdkError> {
+        Self::limit_delay(&env)
+    }
+
+    /// Get the base currency limits, None if spending is only capped per token
+    pub fn get_base_limits(env: Env) -> Result<Option<SpendingLimits>, SdkError> {
+        Self::scope_limits(&env, &LimitScope::Base)
+    }
+
+    /// Get the base currency spending and remaining allowance for every configured window
+    pub fn get_base_limits_status(env: Env) -> Result<Vec<SpendingStatus>, SdkError> {
+        match Self::scope_limits(&env, &LimitScope::Base)? {
+            Some(limits) => Self::limits_status(&env, &DataKey::BaseSpending, &limits),
+            None => Ok(Vec::new(&env)),
+        }
+    }
+
+    /// Get transaction history (last 50 transactions)
+    pub fn get_transaction_history(env: Env) -> Vec<Transaction> {
+        env.storage().instance().get(&DataKey::TransactionHistory).unwrap_or(Vec::new(&env))
+    }
+
+    /// Signature verification for passkey, Ed25519 and Stellar account signers
+    pub fn __check_auth(
+        env: Env,
+        signature_payload: Hash<32>,
+        signatures: Vec<SignerSignature>,
+        auth_contexts: Vec<Context>
+    ) -> Result<(), SdkError> {
+        if signatures.is_empty() {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        }
+
+        let required = Self::required_signatures(&env, &auth_contexts)?;
+        let user_verification = Self::get_settings(&env)?.user_verification;
+        let mut signers = Vec::<Signer>::new(&env);
+
+        for signature in signatures.iter() {
+            let signer = match signature {
+                SignerSignature::Passkey(signature) => {
+                    let signer = Signer::Passkey(signature.id.clone());
+                    Self::verify_passkey(
+                        &env,
+                        &signature_payload,
+                        signature,
+                        &auth_contexts,
+                        &user_verification
+                    )?;
+                    signer
+                }
+                SignerSignature::Ed25519(signature) => {
+                    let signer = Signer::Ed25519(signature.public_key.clone());
+                    Self::verify_ed25519(&env, &signature_payload, signature)?;
+                    signer
+                }
+                SignerSignature::Account(address) => {
+                    let signer = Signer::Account(address.clone());
+                    Self::verify_account(&env, &signature_payload, address)?;
+                    signer
+                }
+                SignerSignature::Session(signature) => {
+                    // Session keys aren't signers, they only stand in for a single signature
+                    if signatures.len() != 1 || required > 1 {
+                        return Err(SdkError::from_contract_error(ERROR_THRESHOLD_NOT_MET));
+                    }
+
+                    // Session keys carry their own scope and budget instead of permissions
+                    Self::verify_session(&env, &signature_payload, signature, &auth_contexts)?;
+                    continue;
+                }
+            };
+
+            if signers.contains(&signer) {
+                return Err(SdkError::from_contract_error(ERROR_DUPLICATE_SIGNATURE));
+            }
+
+            // The signature is valid, now check the signer may authorize these invocations
+            Self::check_permissions(&env, &signer, &auth_contexts)?;
+
+            signers.push_back(signer);
+        }
+
+        // A lone session key signature was already held against the threshold above
+        if !signers.is_empty() && signers.len() < required {
+            return Err(SdkError::from_contract_error(ERROR_THRESHOLD_NOT_MET));
+        }
+
+        // Every attached policy must accept the invocations, whoever signed them
+        Self::check_policies(&env, &signers, &auth_contexts)?;
+
+        // Remember who signed an outgoing transfer, a second signer has to approve a step-up
+        let wallet_address = env.current_contract_address();
+
+        let outgoing = auth_contexts.iter().any(|context| {
+            match context {
+                Context::Contract(contract_context) =>
+                    contract_context.contract == wallet_address &&
+                        Self::outgoing_transfer(&env, &contract_context).is_some(),
+                _ => false,
+            }
+        });
+
+        if outgoing {
+            env.storage().temporary().set(&DataKey::AuthSigners, &signers);
+        }
+
+        // Direct token calls never reach send/withdraw, so their amounts are held against
+        // the daily limit here
+        for context in auth_contexts.iter() {
+            if let Context::Contract(contract_context) = context {
+                if contract_context.contract == wallet_address {
+                    continue;
+                }
+
+                if let Some((token, amount)) = Self::outgoing_transfer(&env, &contract_context) {
+                    if amount > 0 {
+                        // There is no way to hold a direct token call for approval
+                        if Self::requires_step_up(&env, &token, amount)? {
+                            return Err(SdkError::from_contract_error(ERROR_STEP_UP_REQUIRED));
+                        }
+
+                        let value = Self::check_daily_limit(&env, &token, amount)?;
+                        Self::update_daily_spending(&env, &token, amount, value)?;
+
+                        // transfer(from, to, amount) and approve(from, spender, ..) pay someone
+                        let recipient = if contract_context.fn_name == symbol_short!("burn") {
+                            None
+                        } else {
+                            contract_context.args
+                                .get(1)
+                                .and_then(|to| Address::try_from_val(&env, &to).ok())
+                        };
+
+                        Self::check_velocity(&env, recipient.as_ref(), &token, amount)?;
+                    }
+                }
+            }
+        }
+
+        // Extend TTL on successful auth
+        let max_ttl = env.storage().max_ttl();
+        env.storage()
+            .instance()
+            .extend_ttl(max_ttl - WEEK_OF_LEDGERS, max_ttl);
+
+        Ok(())
+    }
+
+    // Helper functions
+
+    /// WebAuthn signature verification
+    fn verify_passkey(
+        env: &Env,
+        signature_payload: &Hash<32>,
+        signature: WebAuthnSignature,
+        auth_contexts: &Vec<Context>,
+        user_verification: &UserVerification
+    ) -> Result<(), SdkError> {
+        // Get the passkey that signed
+        let mut passkeys = Self::get_passkeys(env)?;
+        let mut passkey = passkeys
+            .get(signature.id.clone())
+            .ok_or(SdkError::from_contract_error(ERROR_PASSKEY_NOT_FOUND))?;
+
+        // Verify the signature against the public key
+        let (digest, compact_signature) = Self::signed_digest(env, &signature)?;
+
+        env.crypto().secp256r1_verify(
+            &passkey.public_key,
+            &digest,
+            &BytesN::from_array(env, &compact_signature)
+        );
+
+        let sign_count = Self::verify_assertion(
+            env,
+            &signature_payload.to_array(),
+            &signature,
+            &passkey,
+            auth_contexts,
+            user_verification
+        )?;
+
+        // Remember the latest counter
+        if sign_count != passkey.sign_count {
+            passkey.sign_count = sign_count;
+            passkeys.set(passkey.id.clone(), passkey);
+            env.storage().instance().set(&DataKey::Passkeys, &passkeys);
+        }
+
+        Ok(())
+    }
+
+    /// The digest an assertion signs and its signature in the compact low-S form
+    fn signed_digest(
+        env: &Env,
+        signature: &WebAuthnSignature
+    ) -> Result<(Hash<32>, [u8; 64]), SdkError> {
+        // Create the client data hash (SHA-256 of the client_data_json)
+        let client_data_hash = env.crypto().sha256(&signature.client_data_json);
+
+        // Create the message that was signed (concatenate authenticator_data and client_data_hash)
+        let mut message = Bytes::new(env);
+        message.append(&signature.authenticator_data);
+        message.extend_from_array(&client_data_hash.to_array());
+
+        // Hash the message to get the final verification data
+        let verification_data = env.crypto().sha256(&message);
+
+        // Bring the signature into the compact low-S form secp256r1_verify expects
+        if signature.signature.len() > (secp256r1::MAX_DER_SIGNATURE_LEN as u32) {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        }
+
+        let raw_signature = signature.signature.to_buffer::<{ secp256r1::MAX_DER_SIGNATURE_LEN }>();
+        let compact_signature = secp256r1::normalize_signature(raw_signature.as_slice())
+            .ok_or(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE))?;
+
+        Ok((verification_data, compact_signature))
+    }
+
+    /// WebAuthn checks of a signed assertion shared by `__check_auth` and `verify_message`,
+    /// nothing is stored. Returns the assertion's signature counter.
+    fn verify_assertion(
+        env: &Env,
+        signature_payload: &[u8; 32],
+        signature: &WebAuthnSignature,
+        passkey: &PasskeyCredential,
+        auth_contexts: &Vec<Context>,
+        user_verification: &UserVerification
+    ) -> Result<u32, SdkError> {
+        // 1. Parse client_data_json and verify the type and challenge
+        if signature.client_data_json.len() > MAX_CLIENT_DATA_LEN {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA));
+        }
+
+        let client_data_json = signature.client_data_json
+            .to_buffer::<{ MAX_CLIENT_DATA_LEN as usize }>();
+        let client_data = client_data::parse(client_data_json.as_slice())
+            .map_err(|_| SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA))?;
+
+        if client_data.type_ != b"webauthn.get" {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_CLIENT_DATA));
+        }
+
+        // The challenge must be the base64url (unpadded) encoding of the signature payload,
+        // otherwise the assertion could be replayed to authorize any other invocation
+        let mut expected_challenge = [0u8; 43];
+        base64_urls::encode(&mut expected_challenge, signature_payload);
+
+        if client_data.challenge != expected_challenge {
+            return Err(SdkError::from_contract_error(ERROR_CHALLENGE_MISMATCH));
+        }
+
+        // The assertion must come from one of the wallet's allowed origins
+        let settings = Self::get_settings(env)?;
+        let origin = Bytes::from_slice(env, client_data.origin);
+
+        if !settings.allowed_origins.contains(&origin) {
+            return Err(SdkError::from_contract_error(ERROR_ORIGIN_NOT_ALLOWED));
+        }
+
+        // An allowed origin embedded in an iframe of another site sets crossOrigin, the
+        // embedding page could drive the ceremony
+        if client_data.cross_origin == Some(true) {
+            return Err(SdkError::from_contract_error(ERROR_ORIGIN_NOT_ALLOWED));
+        }
+
+        // 2. Verify the authenticator_data
         // Check minimum length for authenticator_data
         if signature.authenticator_data.len() < 37 {
             return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
         }
 
-        // Check user presence flag (bit 0 of the flags byte)
-        let flags_byte = signature.authenticator_data.get(32).unwrap_or(0);
-        let user_present = (flags_byte & 0x01) != 0;
+        // The first 32 bytes are the SHA-256 of the relying party ID the credential is scoped to,
+        // credentials registered for any other RP ID (e.g. a phishing domain) are rejected
+        let rp_id_hash = Bytes::from_array(env, &env.crypto().sha256(&settings.rp_id).to_array());
+
+        if signature.authenticator_data.slice(0..32) != rp_id_hash {
+            return Err(SdkError::from_contract_error(ERROR_RP_ID_MISMATCH));
+        }
+
+        // Check the signature counter (bytes 33..37, big endian). Authenticators without a
+        // counter, like synced passkeys, always report 0 and are let through
+        let mut sign_count_bytes = [0u8; 4];
+        signature.authenticator_data.slice(33..37).copy_into_slice(&mut sign_count_bytes);
+        let sign_count = u32::from_be_bytes(sign_count_bytes);
+
+        if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+            // A counter that didn't move forward means the credential may have been cloned.
+            // Events of a failed invocation are rolled back with it, so there is no event here.
+            // Monitoring watches for failed transactions with ERROR_SIGN_COUNT_REPLAY instead
+            return Err(SdkError::from_contract_error(ERROR_SIGN_COUNT_REPLAY));
+        }
+
+        // Check user presence flag (bit 0 of the flags byte)
+        let flags_byte = signature.authenticator_data.get(32).unwrap_or(0);
+        let user_present = (flags_byte & 0x01) != 0;
+
+        if !user_present {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        }
+
+        // Check user verification flag (bit 2 of the flags byte) when the policy asks for it
+        let user_verified = (flags_byte & 0x04) != 0;
+
+        if
+            !user_verified &&
+            Self::requires_user_verification(env, user_verification, auth_contexts)
+        {
+            return Err(SdkError::from_contract_error(ERROR_USER_VERIFICATION_REQUIRED));
+        }
+
+        Ok(sign_count)
+    }
+
+    fn verify_ed25519(
+        env: &Env,
+        signature_payload: &Hash<32>,
+        signature: Ed25519Signature
+    ) -> Result<(), SdkError> {
+        let signer = Signer::Ed25519(signature.public_key.clone());
+
+        if !Self::get_signers(env).contains(&signer) {
+            return Err(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND));
+        }
+
+        env.crypto().ed25519_verify(
+            &signature.public_key,
+            &Bytes::from_array(env, &signature_payload.to_array()),
+            &signature.signature
+        );
+
+        Ok(())
+    }
+
+    fn verify_account(
+        env: &Env,
+        signature_payload: &Hash<32>,
+        address: Address
+    ) -> Result<(), SdkError> {
+        let signer = Signer::Account(address.clone());
+
+        if !Self::get_signers(env).contains(&signer) {
+            return Err(SdkError::from_contract_error(ERROR_SIGNER_NOT_FOUND));
+        }
+
+        // Delegate to the account's own auth, scoped to this exact payload
+        let payload = BytesN::from_array(env, &signature_payload.to_array());
+        address.require_auth_for_args((payload,).into_val(env));
+
+        Ok(())
+    }
+
+    fn verify_session(
+        env: &Env,
+        signature_payload: &Hash<32>,
+        signature: Ed25519Signature,
+        auth_contexts: &Vec<Context>
+    ) -> Result<(), SdkError> {
+        let key = DataKey::SessionKey(signature.public_key.clone());
+        let mut session_key: SessionKey = env
+            .storage()
+            .instance()
+            .get(&key)
+            .ok_or(SdkError::from_contract_error(ERROR_SESSION_NOT_FOUND))?;
+
+        if env.ledger().sequence() > session_key.expires_at {
+            return Err(SdkError::from_contract_error(ERROR_SESSION_EXPIRED));
+        }
+
+        env.crypto().ed25519_verify(
+            &signature.public_key,
+            &Bytes::from_array(env, &signature_payload.to_array()),
+            &signature.signature
+        );
+
+        let wallet_address = env.current_contract_address();
+
+        for context in auth_contexts.iter() {
+            // Session keys can only call the allowed functions of this wallet
+            let contract_context = match context {
+                Context::Contract(contract_context) => contract_context,
+                _ => {
+                    return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+                }
+            };
+
+            if
+                contract_context.contract != wallet_address ||
+                !session_key.functions.contains(&contract_context.fn_name)
+            {
+                return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+            }
+
+            // Every spend is taken out of the key's budget for that token
+            if let Some((token, amount)) = Self::outgoing_transfer(env, &contract_context) {
+                let budget = session_key.budgets.get(token.clone()).unwrap_or(0);
+
+                if amount < 0 || amount > budget {
+                    return Err(SdkError::from_contract_error(ERROR_SESSION_BUDGET_EXCEEDED));
+                }
+
+                session_key.budgets.set(token.clone(), budget - amount);
+
+                // Emit event
+                env.events().publish(
+                    (EVENT_TAG, symbol_short!("session")),
+                    (signature.public_key.clone(), token, amount, budget - amount)
+                );
+            }
+        }
+
+        env.storage().instance().set(&key, &session_key);
+
+        Ok(())
+    }
+
+    fn get_passkeys(env: &Env) -> Result<Map<Bytes, PasskeyCredential>, SdkError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Passkeys)
+            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))
+    }
+
+    /// Reject keys that aren't on the curve and decompress compressed ones, so a bad key fails
+    /// at registration instead of on the first payment
+    fn parse_public_key(env: &Env, public_key: &Bytes) -> Result<BytesN<65>, SdkError> {
+        if public_key.len() > (secp256r1::UNCOMPRESSED_KEY_LEN as u32) {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_PUBLIC_KEY));
+        }
+
+        let raw_key = public_key.to_buffer::<{ secp256r1::UNCOMPRESSED_KEY_LEN }>();
+        let uncompressed = secp256r1::parse_public_key(raw_key.as_slice())
+            .ok_or(SdkError::from_contract_error(ERROR_INVALID_PUBLIC_KEY))?;
+
+        Ok(BytesN::from_array(env, &uncompressed))
+    }
+
+    fn store_passkey(env: &Env, passkey_id: Bytes, public_key: Bytes) -> Result<(), SdkError> {
+        let mut passkeys = Self::get_passkeys(env)?;
+
+        if passkeys.contains_key(passkey_id.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_PASSKEY_EXISTS));
+        }
+
+        let passkey = PasskeyCredential {
+            id: passkey_id.clone(),
+            public_key: Self::parse_public_key(env, &public_key)?,
+            created_at: env.ledger().timestamp(),
+            sign_count: 0,
+        };
+
+        passkeys.set(passkey_id, passkey);
+        env.storage().instance().set(&DataKey::Passkeys, &passkeys);
+
+        // Extend TTL
+        let max_ttl = env.storage().max_ttl();
+        env.storage()
+            .instance()
+            .extend_ttl(max_ttl - WEEK_OF_LEDGERS, max_ttl);
+
+        Ok(())
+    }
+
+    fn delete_passkey(env: &Env, passkey_id: &Bytes) -> Result<(), SdkError> {
+        let mut passkeys = Self::get_passkeys(env)?;
+
+        if !passkeys.contains_key(passkey_id.clone()) {
+            return Err(SdkError::from_contract_error(ERROR_PASSKEY_NOT_FOUND));
+        }
+
+        if passkeys.len() == 1 {
+            return Err(SdkError::from_contract_error(ERROR_LAST_PASSKEY));
+        }
+
+        passkeys.remove(passkey_id.clone());
+        env.storage().instance().set(&DataKey::Passkeys, &passkeys);
+
+        Self::check_threshold_reachable(env)?;
+
+        Ok(())
+    }
+
+    fn get_signers(env: &Env) -> Vec<Signer> {
+        env.storage().instance().get(&DataKey::Signers).unwrap_or(Vec::new(env))
+    }
+
+    /// Check an outgoing payment against the velocity rules and count it
+    fn check_velocity(
+        env: &Env,
+        recipient: Option<&Address>,
+        token: &Address,
+        amount: i128
+    ) -> Result<(), SdkError> {
+        let rules = Self::velocity_rules(env)?;
+        let now = env.ledger().timestamp();
+
+        // Payments per hour and per rolling 24 hours
+        let current_hour = now / SECONDS_PER_HOUR;
+        let mut counts: Map<u64, u32> = env
+            .storage()
+            .instance()
+            .get(&DataKey::PaymentCounts)
+            .unwrap_or(Map::new(env));
+
+        for hour in counts.keys().iter() {
+            if hour + 24 <= current_hour {
+                counts.remove(hour);
+            }
+        }
+
+        let this_hour = counts.get(current_hour).unwrap_or(0) + 1;
+        let today: u32 = counts.values().iter().sum::<u32>() + 1;
+
+        if rules.max_per_hour.is_some_and(|max| this_hour > max) {
+            return Err(SdkError::from_contract_error(ERROR_HOURLY_TX_LIMIT));
+        }
+
+        if rules.max_per_day.is_some_and(|max| today > max) {
+            return Err(SdkError::from_contract_error(ERROR_DAILY_TX_LIMIT));
+        }
+
+        counts.set(current_hour, this_hour);
+        env.storage().instance().set(&DataKey::PaymentCounts, &counts);
+
+        let recipient = match recipient {
+            Some(recipient) => recipient,
+            None => {
+                return Ok(());
+            }
+        };
+
+        // Recipients are kept outside instance storage as the list only grows
+        let key = DataKey::Recipient(recipient.clone());
+        let mut record: RecipientRecord = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .unwrap_or(RecipientRecord {
+                first_paid_at: now,
+                sent: Map::new(env),
+            });
+
+        if now < record.first_paid_at.saturating_add(rules.new_recipient_period) {
+            let sent = record.sent.get(token.clone()).unwrap_or(0) + amount;
+
+            // Trusted contacts already waited out their cooldown
+            if let Some(limit) = rules.new_recipient_limits.get(token.clone()) {
+                if sent > limit && Self::trusted_contact(env, recipient).is_none() {
+                    return Err(SdkError::from_contract_error(ERROR_NEW_RECIPIENT_LIMIT));
+                }
+            }
+
+            record.sent.set(token.clone(), sent);
+        }
+
+        env.storage().persistent().set(&key, &record);
+
+        let max_ttl = env.storage().max_ttl();
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, max_ttl - WEEK_OF_LEDGERS, max_ttl);
+
+        Ok(())
+    }
+
+    fn get_session_keys(env: &Env) -> Vec<BytesN<32>> {
+        env.storage().instance().get(&DataKey::SessionKeys).unwrap_or(Vec::new(env))
+    }
+
+    fn get_guardians(env: &Env) -> Map<Address, u64> {
+        env.storage().instance().get(&DataKey::Guardians).unwrap_or(Map::new(env))
+    }
+
+    fn get_recovery_request(env: &Env) -> Result<RecoveryRequest, SdkError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Recovery)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_RECOVERY_PENDING))
+    }
+
+    // Approvals from addresses that are still guardians
+    fn recovery_approvals(env: &Env, recovery_request: &RecoveryRequest) -> u32 {
+        let guardians = Self::get_guardians(env);
+
+        recovery_request.approvals
+            .iter()
+            .filter(|guardian| guardians.contains_key(guardian.clone()))
+            .count() as u32
+    }
+
+    fn get_pending_transfers(env: &Env) -> Map<u32, PendingTransfer> {
+        env.storage().instance().get(&DataKey::PendingTransfers).unwrap_or(Map::new(env))
+    }
+
+    fn requires_step_up(env: &Env, token: &Address, amount: i128) -> Result<bool, SdkError> {
+        let thresholds = Self::step_up_policy(env)?.thresholds;
+
+        Ok(thresholds.get(token.clone()).is_some_and(|threshold| amount > threshold))
+    }
+
+    /// Hold a transfer for approval. Its amount is reserved against the limits right away, so
+    /// approving it later can't be blocked by other spending.
+    fn create_pending(
+        env: &Env,
+        to: Address,
+        token: Address,
+        amount: i128
+    ) -> Result<(), SdkError> {
+        let now = env.ledger().timestamp();
+        let mut pending_transfers = Self::get_pending_transfers(env);
+
+        // Expired transfers no longer reserve anything, drop them to free their slot
+        for transfer in pending_transfers.values().iter() {
+            if transfer.deadline < now {
+                pending_transfers.remove(transfer.id);
+
+                // Emit event
+                env.events().publish((EVENT_TAG, symbol_short!("pend_exp")), transfer.id);
+            }
+        }
+
+        if pending_transfers.len() >= MAX_PENDING_TRANSFERS {
+            return Err(SdkError::from_contract_error(ERROR_TOO_MANY_PENDING));
+        }
 
-        if !user_present {
-            return Err(SdkError::from_contract_error(ERROR_INVALID_SIGNATURE));
+        let value = Self::check_daily_limit(env, &token, amount)?;
+        Self::check_velocity(env, Some(&to), &token, amount)?;
+
+        let id: u32 = env.storage().instance().get(&DataKey::NextPendingId).unwrap_or(0);
+        env.storage().instance().set(&DataKey::NextPendingId, &(id + 1));
+
+        let pending = PendingTransfer {
+            id,
+            to: to.clone(),
+            token: token.clone(),
+            amount,
+            value: value.unwrap_or(0),
+            initiators: env
+                .storage()
+                .temporary()
+                .get(&DataKey::AuthSigners)
+                .unwrap_or(Vec::new(env)),
+            created_at: now,
+            deadline: now.saturating_add(Self::step_up_policy(env)?.approval_window),
+        };
+
+        // The signers belong to this transfer only, a later one must not inherit them
+        env.storage().temporary().remove(&DataKey::AuthSigners);
+
+        pending_transfers.set(id, pending.clone());
+        env.storage().instance().set(&DataKey::PendingTransfers, &pending_transfers);
+
+        // Emit event
+        env.events().publish(
+            (EVENT_TAG, symbol_short!("pending")),
+            (id, to, token, amount, pending.deadline)
+        );
+
+        Ok(())
+    }
+
+    // Amounts held by pending transfers that haven't expired, in the token and base currency
+    fn reserved(env: &Env, token: &Address) -> (i128, i128) {
+        let now = env.ledger().timestamp();
+        let mut reserved = 0;
+        let mut reserved_value = 0;
+
+        for transfer in Self::get_pending_transfers(env).values().iter() {
+            if transfer.deadline < now {
+                continue;
+            }
+
+            if transfer.token == *token {
+                reserved += transfer.amount;
+            }
+            reserved_value += transfer.value;
         }
 
-        // Extend TTL on successful auth
-        let max_ttl = env.storage().max_ttl();
+        (reserved, reserved_value)
+    }
+
+    fn get_contacts(env: &Env) -> Map<Address, Contact> {
+        env.storage().instance().get(&DataKey::Contacts).unwrap_or(Map::new(env))
+    }
+
+    // The recipient's address book entry, if it's past its cooldown
+    fn trusted_contact(env: &Env, address: &Address) -> Option<Contact> {
+        Self::get_contacts(env)
+            .get(address.clone())
+            .filter(|contact| contact.trusted_at <= env.ledger().timestamp())
+    }
+
+    fn check_contact_limit(
+        env: &Env,
+        contact: &Contact,
+        token: &Address,
+        amount: i128
+    ) -> Result<(), SdkError> {
+        let limit = match contact.limits.get(token.clone()) {
+            Some(limit) => limit,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let limits = SpendingLimits {
+            daily: limit,
+            weekly: None,
+            monthly: None,
+        };
+        let key = DataKey::ContactSpending(contact.address.clone(), token.clone());
+
+        Self::check_windows(env, &key, &limits, amount)
+    }
+
+    /// Attached policies, without those whose removal took effect
+    fn get_policies(env: &Env) -> Vec<Address> {
+        let policies: Vec<Address> = env
+            .storage()
+            .instance()
+            .get(&DataKey::Policies)
+            .unwrap_or(Vec::new(env));
+        let removals = Self::policy_removals(env);
+        let now = env.ledger().timestamp();
+
+        let mut attached = Vec::new(env);
+
+        for policy in policies.iter() {
+            if removals.get(policy.clone()).is_none_or(|effective_at| effective_at > now) {
+                attached.push_back(policy);
+            }
+        }
+
+        attached
+    }
+
+    fn policy_removals(env: &Env) -> Map<Address, u64> {
+        env.storage().instance().get(&DataKey::PolicyRemovals).unwrap_or(Map::new(env))
+    }
+
+    fn check_policies(
+        env: &Env,
+        signers: &Vec<Signer>,
+        auth_contexts: &Vec<Context>
+    ) -> Result<(), SdkError> {
+        let wallet_address = env.current_contract_address();
+
+        // Removing a policy is time locked instead, the policy could otherwise refuse it forever
+        let mut contexts = Vec::new(env);
+
+        for context in auth_contexts.iter() {
+            let removes_policy = matches!(
+                &context,
+                Context::Contract(call)
+                    if call.contract == wallet_address &&
+                        call.fn_name == Symbol::new(env, "remove_policy")
+            );
+
+            if !removes_policy {
+                contexts.push_back(context);
+            }
+        }
+
+        if contexts.is_empty() {
+            return Ok(());
+        }
+
+        for policy in Self::get_policies(env).iter() {
+            // A policy that errors or traps denies the authorization just like one that rejects
+            let result = PolicyClient::new(env, &policy).try_check(
+                &wallet_address,
+                &contexts,
+                signers
+            );
+
+            if !matches!(result, Ok(Ok(()))) {
+                return Err(SdkError::from_contract_error(ERROR_POLICY_REJECTED));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_settings(env: &Env) -> Result<WalletSettings, SdkError> {
         env.storage()
             .instance()
-            .extend_ttl(max_ttl - WEEK_OF_LEDGERS, max_ttl);
+            .get(&DataKey::Settings)
+            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))
+    }
+
+    /// Distinct signatures the invocations need, the highest threshold of any context
+    fn required_signatures(env: &Env, auth_contexts: &Vec<Context>) -> Result<u32, SdkError> {
+        let policy = Self::get_settings(env)?.threshold;
+        let wallet_address = env.current_contract_address();
+        let mut required = 1;
+
+        for context in auth_contexts.iter() {
+            let threshold = match context {
+                Context::Contract(call) if call.contract == wallet_address => {
+                    policy.functions.get(call.fn_name).unwrap_or(policy.threshold)
+                }
+                _ => policy.threshold,
+            };
+
+            required = required.max(threshold);
+        }
+
+        Ok(required)
+    }
+
+    /// Every threshold must be at least 1 and satisfiable by the registered signers
+    fn check_threshold_reachable(env: &Env) -> Result<(), SdkError> {
+        let policy = Self::get_settings(env)?.threshold;
+        let signer_count = Self::get_passkeys(env)?.len() + Self::get_signers(env).len();
+        let mut thresholds = policy.functions.values();
+        thresholds.push_back(policy.threshold);
+
+        for threshold in thresholds.iter() {
+            if threshold == 0 || threshold > signer_count {
+                return Err(SdkError::from_contract_error(ERROR_INVALID_THRESHOLD));
+            }
+        }
 
         Ok(())
     }
-    // Helper functions
 
-    fn check_daily_limit(env: &Env, amount: i128) -> Result<(), SdkError> {
-        let settings: WalletSettings = env
+    fn check_permissions(
+        env: &Env,
+        signer: &Signer,
+        auth_contexts: &Vec<Context>
+    ) -> Result<(), SdkError> {
+        let permissions: SignerPermissions = match
+            env.storage().instance().get(&DataKey::Permissions(signer.clone()))
+        {
+            Some(permissions) => permissions,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let wallet_address = env.current_contract_address();
+
+        for context in auth_contexts.iter() {
+            // Restricted signers can't deploy contracts
+            let contract_context = match context {
+                Context::Contract(contract_context) => contract_context,
+                _ => {
+                    return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+                }
+            };
+
+            let function_allowed = if contract_context.contract == wallet_address {
+                // The wallet's own functions (signer and settings management included)
+                // must always be listed explicitly
+                permissions.functions
+                    .as_ref()
+                    .is_some_and(|functions| functions.contains(&contract_context.fn_name))
+            } else {
+                let contract_allowed = permissions.contracts
+                    .as_ref()
+                    .is_none_or(|contracts| contracts.contains(&contract_context.contract));
+
+                contract_allowed &&
+                    permissions.functions
+                        .as_ref()
+                        .is_none_or(|functions| functions.contains(&contract_context.fn_name))
+            };
+
+            if !function_allowed {
+                return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+            }
+
+            if let Some((token, amount)) = Self::outgoing_transfer(env, &contract_context) {
+                if let Some(cap) = permissions.amount_caps.get(token) {
+                    if amount > cap {
+                        return Err(SdkError::from_contract_error(ERROR_PERMISSION_DENIED));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn requires_user_verification(
+        env: &Env,
+        policy: &UserVerification,
+        auth_contexts: &Vec<Context>
+    ) -> bool {
+        match policy {
+            UserVerification::Always => true,
+            UserVerification::Never => false,
+            UserVerification::AboveAmount(threshold) => {
+                let mut total: i128 = 0;
+
+                for context in auth_contexts.iter() {
+                    // Anything that doesn't move tokens out (settings changes, other contract
+                    // calls) can't be valued here, so it always needs UV
+                    let amount = match &context {
+                        Context::Contract(contract_context) =>
+                            Self::outgoing_transfer(env, contract_context),
+                        _ => None,
+                    };
+
+                    match amount {
+                        Some((_, amount)) => {
+                            total = total.saturating_add(amount);
+                        }
+                        None => {
+                            return true;
+                        }
+                    }
+                }
+
+                total > *threshold
+            }
+        }
+    }
+
+    /// Token and amount leaving the wallet through a send/withdraw/send_with_fee on this wallet
+    /// or a direct SEP-41 transfer/approve/burn of the wallet's balance, None for any other call
+    fn outgoing_transfer(env: &Env, context: &ContractContext) -> Option<(Address, i128)> {
+        let wallet_address = env.current_contract_address();
+
+        let (token, amount) = if context.contract == wallet_address {
+            if context.fn_name == symbol_short!("send") {
+                // send(to_wallet, token, amount)
+                (context.args.get(1)?, context.args.get(2)?)
+            } else if context.fn_name == symbol_short!("withdraw") {
+                // withdraw(token, amount, destination)
+                (context.args.get(0)?, context.args.get(1)?)
+            } else if context.fn_name == Symbol::new(env, "send_with_fee") {
+                // Authorized as (to_wallet, token, amount, relayer, max_fee), the relayer can
+                // charge up to max_fee so that is what gets counted
+                let amount = i128::try_from_val(env, &context.args.get(2)?).ok()?;
+                let max_fee = i128::try_from_val(env, &context.args.get(4)?).ok()?;
+                (context.args.get(1)?, amount.checked_add(max_fee)?.into_val(env))
+            } else {
+                return None;
+            }
+        } else {
+            // transfer(from, to, amount), approve(from, spender, amount, expiration_ledger)
+            // and burn(from, amount)
+            let amount_index = if
+                context.fn_name == symbol_short!("transfer") ||
+                context.fn_name == symbol_short!("approve")
+            {
+                2
+            } else if context.fn_name == symbol_short!("burn") {
+                1
+            } else {
+                return None;
+            };
+
+            let from = Address::try_from_val(env, &context.args.get(0)?).ok()?;
+
+            if from != wallet_address {
+                return None;
+            }
+
+            (context.contract.to_val(), context.args.get(amount_index)?)
+        };
+
+        Some((Address::try_from_val(env, &token).ok()?, i128::try_from_val(env, &amount).ok()?))
+    }
+
+    fn get_limits(env: &Env, token: &Address) -> Result<SpendingLimits, SdkError> {
+        Self::scope_limits(env, &LimitScope::Token(token.clone()))?.ok_or(
+            SdkError::from_contract_error(ERROR_NOT_INITIALIZED)
+        )
+    }
+
+    // Limits in force, including a queued increase whose delay has passed. Tokens always have
+    // limits, the base currency only once some were set.
+    fn scope_limits(env: &Env, scope: &LimitScope) -> Result<Option<SpendingLimits>, SdkError> {
+        let pending: Option<PendingLimits> = env
             .storage()
             .instance()
-            .get(&DataKey::Settings)
-            .ok_or(SdkError::from_contract_error(ERROR_NOT_INITIALIZED))?;
+            .get(&DataKey::PendingLimits(scope.clone()));
 
-        let current_spending = Self::get_daily_spending(env.clone());
+        if let Some(pending) = pending {
+            if pending.effective_at <= env.ledger().timestamp() {
+                return Ok(Some(pending.limits));
+            }
+        }
 
-        if current_spending + amount > settings.daily_limit {
-            return Err(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED));
+        match scope {
+            LimitScope::Token(token) => {
+                let limits = Self::get_settings(env)?.limits.get(token.clone());
+
+                Ok(
+                    Some(
+                        limits.unwrap_or(SpendingLimits {
+                            daily: MAX_DAILY_LIMIT,
+                            weekly: None,
+                            monthly: None,
+                        })
+                    )
+                )
+            }
+            LimitScope::Base => Ok(env.storage().instance().get(&DataKey::BaseLimits)),
+        }
+    }
+
+    fn store_limits(
+        env: &Env,
+        scope: &LimitScope,
+        limits: &SpendingLimits
+    ) -> Result<(), SdkError> {
+        match scope {
+            LimitScope::Token(token) => {
+                let mut settings = Self::get_settings(env)?;
+                settings.limits.set(token.clone(), limits.clone());
+                env.storage().instance().set(&DataKey::Settings, &settings);
+            }
+            LimitScope::Base => {
+                env.storage().instance().set(&DataKey::BaseLimits, limits);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The tighter of two optional caps, None being no cap
+    fn lower_cap<T: Ord>(current: Option<T>, new: Option<T>) -> Option<T> {
+        match (current, new) {
+            (Some(current), Some(new)) => Some(current.min(new)),
+            (Some(current), None) => Some(current),
+            (None, new) => new,
+        }
+    }
+
+    // Delay in force, including a queued shorter delay whose wait has passed
+    fn limit_delay(env: &Env) -> Result<u64, SdkError> {
+        let pending: Option<PendingLimitDelay> = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingLimitDelay);
+
+        if let Some(pending) = pending {
+            if pending.effective_at <= env.ledger().timestamp() {
+                return Ok(pending.delay);
+            }
+        }
+
+        Ok(Self::get_settings(env)?.limit_delay)
+    }
+
+    fn check_limits_valid(limits: &SpendingLimits) -> Result<(), SdkError> {
+        if
+            limits.daily < 0 ||
+            limits.weekly.is_some_and(|limit| limit < 0) ||
+            limits.monthly.is_some_and(|limit| limit < 0)
+        {
+            return Err(SdkError::from_contract_error(ERROR_INVALID_AMOUNT));
         }
 
         Ok(())
     }
 
-    fn update_daily_spending(env: &Env, amount: i128) -> Result<(), SdkError> {
-        let today = env.ledger().timestamp() / (24 * 60 * 60);
-        let current_spending = Self::get_daily_spending(env.clone());
+    fn spending_status(window: LimitWindow, spent: i128, limit: i128) -> SpendingStatus {
+        SpendingStatus {
+            window,
+            spent,
+            limit,
+            remaining: (limit - spent).max(0),
+        }
+    }
+
+    // Base currency value of an outgoing amount, None while no base limits are set
+    fn base_value(env: &Env, token: &Address, amount: i128) -> Result<Option<i128>, SdkError> {
+        if !env.storage().instance().has(&DataKey::BaseLimits) {
+            return Ok(None);
+        }
+
+        let user_manager: Address = env
+            .storage()
+            .instance()
+            .get(&DataKey::UserManager)
+            .ok_or(SdkError::from_contract_error(ERROR_NO_PRICE_SOURCE))?;
+
+        Ok(Some(UserManagerClient::new(env, &user_manager).value_of(token, &amount)))
+    }
+
+    fn limits_status(
+        env: &Env,
+        key: &DataKey,
+        limits: &SpendingLimits
+    ) -> Result<Vec<SpendingStatus>, SdkError> {
+        let mut status = Vec::<SpendingStatus>::new(env);
+
+        let spent = Self::spent_in_window(env, key)?;
+        status.push_back(Self::spending_status(LimitWindow::Daily, spent, limits.daily));
+
+        if let Some(limit) = limits.weekly {
+            let spent = Self::spent_in_days(env, key, 7)?;
+            status.push_back(Self::spending_status(LimitWindow::Weekly, spent, limit));
+        }
+
+        if let Some(limit) = limits.monthly {
+            let spent = Self::spent_in_days(env, key, 30)?;
+            status.push_back(Self::spending_status(LimitWindow::Monthly, spent, limit));
+        }
+
+        Ok(status)
+    }
+
+    fn get_daily_spending_record(env: &Env, key: &DataKey) -> DailySpending {
+        env.storage()
+            .instance()
+            .get(key)
+            .unwrap_or(DailySpending {
+                hours: Map::new(env),
+                days: Map::new(env),
+            })
+    }
+
+    // The configured window, or a queued change once its delay has passed
+    fn spending_window(env: &Env) -> Result<SpendingWindow, SdkError> {
+        let pending: Option<PendingSpendingWindow> = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingSpendingWindow);
+
+        if let Some(pending) = pending {
+            if pending.effective_at <= env.ledger().timestamp() {
+                return Ok(pending.window);
+            }
+        }
+
+        Ok(Self::get_settings(env)?.spending_window)
+    }
+
+    // Step-up policy in force, including a queued looser policy whose wait has passed
+    fn step_up_policy(env: &Env) -> Result<StepUpPolicy, SdkError> {
+        let pending: Option<PendingStepUp> = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingStepUp);
+
+        if let Some(pending) = pending {
+            if pending.effective_at <= env.ledger().timestamp() {
+                return Ok(pending.policy);
+            }
+        }
+
+        Ok(Self::get_settings(env)?.step_up)
+    }
+
+    // Velocity rules in force, including queued looser rules whose wait has passed
+    fn velocity_rules(env: &Env) -> Result<VelocityRules, SdkError> {
+        let pending: Option<PendingVelocity> = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingVelocity);
+
+        if let Some(pending) = pending {
+            if pending.effective_at <= env.ledger().timestamp() {
+                return Ok(pending.rules);
+            }
+        }
+
+        Ok(Self::get_settings(env)?.velocity)
+    }
+
+    fn spent_in_window(env: &Env, key: &DataKey) -> Result<i128, SdkError> {
+        let now = env.ledger().timestamp();
 
-        let new_spending = DailySpending {
-            date: today,
-            amount: current_spending + amount,
+        // First hour of the window. Partial hours at either end count whole, erring on the side
+        // of the limit: a rolling window keeps a payment for 24 to 25 hours.
+        let first_hour = match Self::spending_window(env)? {
+            SpendingWindow::Rolling => (now / SECONDS_PER_HOUR).saturating_sub(24),
+            SpendingWindow::Calendar(utc_offset) => {
+                let since_midnight = ((now as i64) + utc_offset).rem_euclid(
+                    SECONDS_PER_DAY as i64
+                ) as u64;
+
+                (now - since_midnight) / SECONDS_PER_HOUR
+            }
         };
 
-        env.storage().instance().set(&DataKey::DailySpending, &new_spending);
+        Ok(
+            Self::get_daily_spending_record(env, key)
+                .hours.iter()
+                .filter(|(hour, _)| *hour >= first_hour)
+                .map(|(_, amount)| amount)
+                .sum()
+        )
+    }
+
+    // Spent over the last `days` UTC days, today included
+    fn spent_in_days(env: &Env, key: &DataKey, days: u64) -> Result<i128, SdkError> {
+        let today = env.ledger().timestamp() / SECONDS_PER_DAY;
+
+        Ok(
+            Self::get_daily_spending_record(env, key)
+                .days.iter()
+                .filter(|(day, _)| day + days > today)
+                .map(|(_, amount)| amount)
+                .sum()
+        )
+    }
+
+    /// Check the token's limits and, if set, the base currency limits. The amount has to fit
+    /// in every configured window. Returns the base currency value so it is priced only once.
+    fn check_daily_limit(
+        env: &Env,
+        token: &Address,
+        amount: i128
+    ) -> Result<Option<i128>, SdkError> {
+        // Pending transfers count as already spent
+        let (reserved, reserved_value) = Self::reserved(env, token);
+
+        let limits = Self::get_limits(env, token)?;
+        let key = DataKey::DailySpending(token.clone());
+        Self::check_windows(env, &key, &limits, amount + reserved)?;
+
+        let value = Self::base_value(env, token, amount)?;
+
+        if let Some(value) = value {
+            let base_limits = Self::scope_limits(env, &LimitScope::Base)?.ok_or(
+                SdkError::from_contract_error(ERROR_NO_PRICE_SOURCE)
+            )?;
+
+            Self::check_windows(env, &DataKey::BaseSpending, &base_limits, value + reserved_value)?;
+        }
+
+        Ok(value)
+    }
+
+    fn check_windows(
+        env: &Env,
+        key: &DataKey,
+        limits: &SpendingLimits,
+        amount: i128
+    ) -> Result<(), SdkError> {
+        if Self::spent_in_window(env, key)? + amount > limits.daily {
+            return Err(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED));
+        }
+
+        if let Some(limit) = limits.weekly {
+            if Self::spent_in_days(env, key, 7)? + amount > limit {
+                return Err(SdkError::from_contract_error(ERROR_WEEKLY_LIMIT_EXCEEDED));
+            }
+        }
+
+        if let Some(limit) = limits.monthly {
+            if Self::spent_in_days(env, key, 30)? + amount > limit {
+                return Err(SdkError::from_contract_error(ERROR_MONTHLY_LIMIT_EXCEEDED));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_daily_spending(
+        env: &Env,
+        token: &Address,
+        amount: i128,
+        value: Option<i128>
+    ) -> Result<(), SdkError> {
+        Self::record_spending(env, &DataKey::DailySpending(token.clone()), amount)?;
+
+        if let Some(value) = value {
+            Self::record_spending(env, &DataKey::BaseSpending, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn record_spending(env: &Env, key: &DataKey, amount: i128) -> Result<(), SdkError> {
+        let mut spending = Self::get_daily_spending_record(env, key);
+        let today = env.ledger().timestamp() / SECONDS_PER_DAY;
+
+        // Hourly buckets. A calendar day in any offset starts within the last 25 hours, anything
+        // older is dropped so storage stays bounded
+        let current_hour = env.ledger().timestamp() / SECONDS_PER_HOUR;
+
+        for hour in spending.hours.keys().iter() {
+            if hour + 25 <= current_hour {
+                spending.hours.remove(hour);
+            }
+        }
+
+        let spent_this_hour = spending.hours.get(current_hour).unwrap_or(0);
+        spending.hours.set(current_hour, spent_this_hour + amount);
+
+        // Daily buckets for the weekly and monthly caps, kept for 30 days
+        for day in spending.days.keys().iter() {
+            if day + 30 <= today {
+                spending.days.remove(day);
+            }
+        }
+
+        let spent_today = spending.days.get(today).unwrap_or(0);
+        spending.days.set(today, spent_today + amount);
+
+        env.storage().instance().set(key, &spending);
         Ok(())
     }
 
@@ -0,0 +1,911 @@
+// secp256r1 (P-256) helpers for WebAuthn assertions.
+//
+// Authenticators return ECDSA signatures ASN.1 DER encoded and with either S value, while the
+// host's `secp256r1_verify` only accepts the 64 byte `r || s` form with a low S. Normalizing
+// here means every client gets the same behavior without reimplementing it.
+//
+// Public keys get the same treatment: `secp256r1_verify` only takes uncompressed SEC1 points,
+// so compressed keys are decompressed here and every key is checked to be on the curve
+// before it is stored.
+//
+// `verify` checks a signature in contract code. The host function traps on an invalid
+// signature, which is right for authorization but not for a call that answers true or false.
+
+// Curve order n, big endian
+const ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+// n / 2, the largest S value considered "low"
+const HALF_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xde, 0x73, 0x7d, 0x56, 0xd3, 0x8b, 0xcf, 0x42, 0x79, 0xdc, 0xe5, 0x61, 0x7e, 0x31, 0x92, 0xa8,
+];
+
+// Field prime p = 2^256 - 2^224 + 2^192 + 2^96 - 1, little endian 64 bit limbs
+const P: Fe = [
+    0xffffffffffffffff, 0x00000000ffffffff, 0x0000000000000000, 0xffffffff00000001,
+];
+
+// R^2 mod p for the Montgomery radix R = 2^256
+const R2: Fe = [
+    0x0000000000000003, 0xfffffffbffffffff, 0xfffffffffffffffe, 0x00000004fffffffd,
+];
+
+// Curve coefficient b of y^2 = x^3 - 3x + b
+const B: Fe = [
+    0x3bce3c3e27d2604b, 0x651d06b0cc53b0f6, 0xb3ebbd55769886bc, 0x5ac635d8aa3a93e7,
+];
+
+// (p + 1) / 4, since p = 3 mod 4 a square root is a single exponentiation
+const SQRT_EXP: Fe = [
+    0x0000000000000000, 0x0000000040000000, 0x4000000000000000, 0x3fffffffc0000000,
+];
+
+// Curve order n, little endian 64 bit limbs
+const N: Fe = [
+    0xf3b9cac2fc632551, 0xbce6faada7179e84, 0xffffffffffffffff, 0xffffffff00000000,
+];
+
+// -n^-1 mod 2^64
+const N_INV: u64 = 0xccd1c8aaee00bc4f;
+
+// R^2 mod n
+const R2_N: Fe = [
+    0x83244c95be79eea2, 0x4699799c49bd6fa6, 0x2845b2392b6bec59, 0x66e12d94f3d95620,
+];
+
+// Base point G
+const GX: Fe = [
+    0xf4a13945d898c296, 0x77037d812deb33a0, 0xf8bce6e563a440f2, 0x6b17d1f2e12c4247,
+];
+const GY: Fe = [
+    0xcbb6406837bf51f5, 0x2bce33576b315ece, 0x8ee7eb4a7c0f9e16, 0x4fe342e2fe1a7f9b,
+];
+
+pub const COMPACT_SIGNATURE_LEN: usize = 64;
+pub const MAX_DER_SIGNATURE_LEN: usize = 72;
+pub const COMPRESSED_KEY_LEN: usize = 33;
+pub const UNCOMPRESSED_KEY_LEN: usize = 65;
+
+// Field element, little endian 64 bit limbs
+type Fe = [u64; 4];
+
+// Jacobian point (X / Z^2, Y / Z^3) with Montgomery coordinates, Z = 0 is the identity
+type Point = [Fe; 3];
+
+/// Convert a compact (`r || s`) or DER encoded signature into the compact low-S form
+pub fn normalize_signature(signature: &[u8]) -> Option<[u8; 64]> {
+    let mut r = [0u8; 32];
+    let mut s = [0u8; 32];
+
+    if signature.len() == COMPACT_SIGNATURE_LEN {
+        r.copy_from_slice(&signature[..32]);
+        s.copy_from_slice(&signature[32..]);
+    } else {
+        parse_der(signature, &mut r, &mut s)?;
+    }
+
+    if !is_scalar(&r) || !is_scalar(&s) {
+        return None;
+    }
+
+    if s > HALF_ORDER {
+        s = sub(&ORDER, &s);
+    }
+
+    let mut compact = [0u8; 64];
+    compact[..32].copy_from_slice(&r);
+    compact[32..].copy_from_slice(&s);
+
+    Some(compact)
+}
+
+/// Validate a SEC1 public key (0x04 uncompressed or 0x02/0x03 compressed) and return it
+/// uncompressed, or None if it isn't a point on the curve
+pub fn parse_public_key(key: &[u8]) -> Option<[u8; 65]> {
+    let (x, y) = match (key.len(), key.first()) {
+        (UNCOMPRESSED_KEY_LEN, Some(0x04)) => {
+            let x = fe_from_be(&key[1..33])?;
+            let y = fe_from_be(&key[33..65])?;
+
+            if mont_mul(&y, &y) != curve_rhs(&x) {
+                return None;
+            }
+
+            (x, y)
+        }
+        (COMPRESSED_KEY_LEN, Some(prefix @ (0x02 | 0x03))) => {
+            let x = fe_from_be(&key[1..33])?;
+            let rhs = curve_rhs(&x);
+            let mut y = pow(&rhs, &SQRT_EXP);
+
+            // Not every x has a point, then rhs has no square root
+            if mont_mul(&y, &y) != rhs {
+                return None;
+            }
+
+            // The prefix picks the root with the matching parity
+            if (from_mont(&y)[0] & 1) != ((*prefix as u64) & 1) {
+                y = sub_mod(&[0; 4], &y);
+            }
+
+            (x, y)
+        }
+        _ => {
+            return None;
+        }
+    };
+
+    let mut uncompressed = [0u8; 65];
+    uncompressed[0] = 0x04;
+    uncompressed[1..33].copy_from_slice(&fe_to_be(&from_mont(&x)));
+    uncompressed[33..65].copy_from_slice(&fe_to_be(&from_mont(&y)));
+
+    Some(uncompressed)
+}
+
+/// ECDSA verification of a compact low-S signature over a SHA-256 digest, for a key returned
+/// by `parse_public_key`. Returns false instead of trapping.
+pub fn verify(public_key: &[u8; 65], digest: &[u8; 32], signature: &[u8; 64]) -> bool {
+    let r = limbs_from_be(&signature[..32]);
+    let s = limbs_from_be(&signature[32..]);
+
+    if r == [0; 4] || s == [0; 4] || !fe_lt(&r, &N) || !fe_lt(&s, &N) {
+        return false;
+    }
+
+    let (Some(x), Some(y)) = (fe_from_be(&public_key[1..33]), fe_from_be(&public_key[33..])) else {
+        return false;
+    };
+
+    if public_key[0] != 0x04 || mont_mul(&y, &y) != curve_rhs(&x) {
+        return false;
+    }
+
+    // e = digest mod n, a single subtraction since the digest is below 2n
+    let mut e = limbs_from_be(digest);
+
+    if !fe_lt(&e, &N) {
+        e = sub_raw(&e, &N).0;
+    }
+
+    // u1 = e / s and u2 = r / s
+    let s_inv = scalar_pow(&scalar_mul(&s, &R2_N), &sub_raw(&N, &[2, 0, 0, 0]).0);
+    let u1 = scalar_mul(&scalar_mul(&e, &R2_N), &s_inv);
+    let u2 = scalar_mul(&scalar_mul(&r, &R2_N), &s_inv);
+    let (u1, u2) = (scalar_mul(&u1, &[1, 0, 0, 0]), scalar_mul(&u2, &[1, 0, 0, 0]));
+
+    // u1 * G + u2 * Q, both multiplications in one pass
+    let one = mont_mul(&[1, 0, 0, 0], &R2);
+    let g = [mont_mul(&GX, &R2), mont_mul(&GY, &R2), one];
+    let q = [x, y, one];
+    let g_plus_q = point_add(&g, &q);
+    let mut sum = [[0; 4], one, [0; 4]];
+
+    for i in (0..256).rev() {
+        sum = point_double(&sum);
+
+        match ((u1[i / 64] >> (i % 64)) & 1, (u2[i / 64] >> (i % 64)) & 1) {
+            (1, 1) => sum = point_add(&sum, &g_plus_q),
+            (1, 0) => sum = point_add(&sum, &g),
+            (0, 1) => sum = point_add(&sum, &q),
+            _ => {}
+        }
+    }
+
+    if sum[2] == [0; 4] {
+        return false;
+    }
+
+    // The affine x, reduced mod n, has to be r
+    let z_inv = pow(&sum[2], &sub_raw(&P, &[2, 0, 0, 0]).0);
+    let mut x = from_mont(&mont_mul(&sum[0], &mont_mul(&z_inv, &z_inv)));
+
+    if !fe_lt(&x, &N) {
+        x = sub_raw(&x, &N).0;
+    }
+
+    x == r
+}
+
+// SEQUENCE { INTEGER r, INTEGER s } with short form lengths only, which covers every P-256
+// signature
+fn parse_der(der: &[u8], r: &mut [u8; 32], s: &mut [u8; 32]) -> Option<()> {
+    if der.len() > MAX_DER_SIGNATURE_LEN || der.len() < 2 || der[0] != 0x30 {
+        return None;
+    }
+
+    if (der[1] as usize) != der.len() - 2 {
+        return None;
+    }
+
+    let rest = parse_der_integer(&der[2..], r)?;
+    let rest = parse_der_integer(rest, s)?;
+
+    if !rest.is_empty() {
+        return None;
+    }
+
+    Some(())
+}
+
+fn parse_der_integer<'a>(der: &'a [u8], out: &mut [u8; 32]) -> Option<&'a [u8]> {
+    if der.len() < 2 || der[0] != 0x02 {
+        return None;
+    }
+
+    let len = der[1] as usize;
+
+    if len == 0 || len > 33 || der.len() < 2 + len {
+        return None;
+    }
+
+    let mut value = &der[2..2 + len];
+
+    // Negative integers are never valid scalars
+    if value[0] & 0x80 != 0 {
+        return None;
+    }
+
+    // A leading zero is only allowed (and required) to keep the high bit clear
+    if value[0] == 0 && len > 1 {
+        if value[1] & 0x80 == 0 {
+            return None;
+        }
+        value = &value[1..];
+    }
+
+    if value.len() > 32 {
+        return None;
+    }
+
+    out[32 - value.len()..].copy_from_slice(value);
+
+    Some(&der[2 + len..])
+}
+
+// 0 < value < n
+fn is_scalar(value: &[u8; 32]) -> bool {
+    value.iter().any(|byte| *byte != 0) && *value < ORDER
+}
+
+// a - b for big endian a >= b
+fn sub(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    let mut borrow = 0i16;
+
+    for i in (0..32).rev() {
+        let mut diff = (a[i] as i16) - (b[i] as i16) - borrow;
+
+        if diff < 0 {
+            diff += 256;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+
+        result[i] = diff as u8;
+    }
+
+    result
+}
+
+// Field arithmetic mod p. Values are kept in Montgomery form (a * R mod p) so reductions are
+// just multiplications.
+
+// Big endian bytes into the Montgomery domain, None unless the value is below p
+fn fe_from_be(bytes: &[u8]) -> Option<Fe> {
+    let value = limbs_from_be(bytes);
+
+    if !fe_lt(&value, &P) {
+        return None;
+    }
+
+    Some(mont_mul(&value, &R2))
+}
+
+fn limbs_from_be(bytes: &[u8]) -> Fe {
+    let mut value = [0u64; 4];
+
+    for (i, chunk) in bytes.chunks_exact(8).enumerate() {
+        let mut limb = [0u8; 8];
+        limb.copy_from_slice(chunk);
+        value[3 - i] = u64::from_be_bytes(limb);
+    }
+
+    value
+}
+
+fn fe_to_be(value: &Fe) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+
+    for i in 0..4 {
+        bytes[i * 8..(i + 1) * 8].copy_from_slice(&value[3 - i].to_be_bytes());
+    }
+
+    bytes
+}
+
+fn from_mont(value: &Fe) -> Fe {
+    mont_mul(value, &[1, 0, 0, 0])
+}
+
+// x^3 - 3x + b
+fn curve_rhs(x: &Fe) -> Fe {
+    let x3 = mont_mul(&mont_mul(x, x), x);
+    let three_x = add_mod(&add_mod(x, x), x);
+
+    add_mod(&sub_mod(&x3, &three_x), &mont_mul(&B, &R2))
+}
+
+fn fe_lt(a: &Fe, b: &Fe) -> bool {
+    for i in (0..4).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+
+    false
+}
+
+// a - b without reduction, returns the borrow
+fn sub_raw(a: &Fe, b: &Fe) -> (Fe, bool) {
+    let mut result = [0u64; 4];
+    let mut borrow = false;
+
+    for i in 0..4 {
+        let (diff, b1) = a[i].overflowing_sub(b[i]);
+        let (diff, b2) = diff.overflowing_sub(borrow as u64);
+        result[i] = diff;
+        borrow = b1 || b2;
+    }
+
+    (result, borrow)
+}
+
+fn add_mod(a: &Fe, b: &Fe) -> Fe {
+    let mut result = [0u64; 4];
+    let mut carry = false;
+
+    for i in 0..4 {
+        let (sum, c1) = a[i].overflowing_add(b[i]);
+        let (sum, c2) = sum.overflowing_add(carry as u64);
+        result[i] = sum;
+        carry = c1 || c2;
+    }
+
+    if carry || !fe_lt(&result, &P) {
+        result = sub_raw(&result, &P).0;
+    }
+
+    result
+}
+
+fn sub_mod(a: &Fe, b: &Fe) -> Fe {
+    let (result, borrow) = sub_raw(a, b);
+
+    if borrow {
+        // Adding p wraps back around 2^256
+        let mut wrapped = [0u64; 4];
+        let mut carry = false;
+
+        for i in 0..4 {
+            let (sum, c1) = result[i].overflowing_add(P[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            wrapped[i] = sum;
+            carry = c1 || c2;
+        }
+
+        return wrapped;
+    }
+
+    result
+}
+
+// a * b / R mod p. -p^-1 mod 2^64 is 1 because the low limb of p is all ones.
+fn mont_mul(a: &Fe, b: &Fe) -> Fe {
+    mont_mul_mod(a, b, &P, 1)
+}
+
+// a * b / R mod n, for the scalars of a signature
+fn scalar_mul(a: &Fe, b: &Fe) -> Fe {
+    mont_mul_mod(a, b, &N, N_INV)
+}
+
+// a * b / R mod m (CIOS), `inv` is -m^-1 mod 2^64
+fn mont_mul_mod(a: &Fe, b: &Fe, modulus: &Fe, inv: u64) -> Fe {
+    let mut t = [0u64; 6];
+
+    for bi in b.iter() {
+        let mut carry = 0u128;
+
+        for j in 0..4 {
+            let v = (t[j] as u128) + (a[j] as u128) * (*bi as u128) + carry;
+            t[j] = v as u64;
+            carry = v >> 64;
+        }
+
+        let v = (t[4] as u128) + carry;
+        t[4] = v as u64;
+        t[5] = (v >> 64) as u64;
+
+        let m = t[0].wrapping_mul(inv) as u128;
+        let mut carry = ((t[0] as u128) + m * (modulus[0] as u128)) >> 64;
+
+        for j in 1..4 {
+            let v = (t[j] as u128) + m * (modulus[j] as u128) + carry;
+            t[j - 1] = v as u64;
+            carry = v >> 64;
+        }
+
+        let v = (t[4] as u128) + carry;
+        t[3] = v as u64;
+        t[4] = t[5] + ((v >> 64) as u64);
+    }
+
+    let result = [t[0], t[1], t[2], t[3]];
+
+    if t[4] != 0 || !fe_lt(&result, modulus) {
+        return sub_raw(&result, modulus).0;
+    }
+
+    result
+}
+
+fn pow(base: &Fe, exponent: &Fe) -> Fe {
+    // R mod p, i.e. 1 in the Montgomery domain
+    let mut result = mont_mul(&[1, 0, 0, 0], &R2);
+
+    for i in (0..256).rev() {
+        result = mont_mul(&result, &result);
+
+        if (exponent[i / 64] >> (i % 64)) & 1 == 1 {
+            result = mont_mul(&result, base);
+        }
+    }
+
+    result
+}
+
+// base^exponent mod n, in the Montgomery domain
+fn scalar_pow(base: &Fe, exponent: &Fe) -> Fe {
+    let mut result = scalar_mul(&[1, 0, 0, 0], &R2_N);
+
+    for i in (0..256).rev() {
+        result = scalar_mul(&result, &result);
+
+        if (exponent[i / 64] >> (i % 64)) & 1 == 1 {
+            result = scalar_mul(&result, base);
+        }
+    }
+
+    result
+}
+
+// 2P for a curve with a = -3 (dbl-2001-b), the identity stays the identity
+fn point_double(point: &Point) -> Point {
+    let [x, y, z] = point;
+
+    let delta = mont_mul(z, z);
+    let gamma = mont_mul(y, y);
+    let beta = mont_mul(x, &gamma);
+    let alpha = mont_mul(&sub_mod(x, &delta), &add_mod(x, &delta));
+    let alpha = add_mod(&add_mod(&alpha, &alpha), &alpha);
+
+    let beta4 = add_mod(&add_mod(&beta, &beta), &add_mod(&beta, &beta));
+    let x3 = sub_mod(&mont_mul(&alpha, &alpha), &add_mod(&beta4, &beta4));
+
+    let y_plus_z = add_mod(y, z);
+    let z3 = sub_mod(&sub_mod(&mont_mul(&y_plus_z, &y_plus_z), &gamma), &delta);
+
+    let gamma2 = mont_mul(&gamma, &gamma);
+    let gamma2_8 = add_mod(&gamma2, &gamma2);
+    let gamma2_8 = add_mod(&gamma2_8, &gamma2_8);
+    let gamma2_8 = add_mod(&gamma2_8, &gamma2_8);
+    let y3 = sub_mod(&mont_mul(&alpha, &sub_mod(&beta4, &x3)), &gamma2_8);
+
+    [x3, y3, z3]
+}
+
+// P + Q (add-2007-bl)
+fn point_add(p1: &Point, p2: &Point) -> Point {
+    if p1[2] == [0; 4] {
+        return *p2;
+    }
+
+    if p2[2] == [0; 4] {
+        return *p1;
+    }
+
+    let z1z1 = mont_mul(&p1[2], &p1[2]);
+    let z2z2 = mont_mul(&p2[2], &p2[2]);
+    let u1 = mont_mul(&p1[0], &z2z2);
+    let u2 = mont_mul(&p2[0], &z1z1);
+    let s1 = mont_mul(&mont_mul(&p1[1], &p2[2]), &z2z2);
+    let s2 = mont_mul(&mont_mul(&p2[1], &p1[2]), &z1z1);
+
+    let h = sub_mod(&u2, &u1);
+    let r = sub_mod(&s2, &s1);
+
+    // Same x: either the same point or its negation
+    if h == [0; 4] {
+        if r == [0; 4] {
+            return point_double(p1);
+        }
+
+        return [[0; 4], mont_mul(&[1, 0, 0, 0], &R2), [0; 4]];
+    }
+
+    let r = add_mod(&r, &r);
+    let h2 = add_mod(&h, &h);
+    let i = mont_mul(&h2, &h2);
+    let j = mont_mul(&h, &i);
+    let v = mont_mul(&u1, &i);
+
+    let x3 = sub_mod(&sub_mod(&mont_mul(&r, &r), &j), &add_mod(&v, &v));
+    let s1j = mont_mul(&s1, &j);
+    let y3 = sub_mod(&mont_mul(&r, &sub_mod(&v, &x3)), &add_mod(&s1j, &s1j));
+
+    let z1_plus_z2 = add_mod(&p1[2], &p2[2]);
+    let z3 = mont_mul(
+        &sub_mod(&sub_mod(&mont_mul(&z1_plus_z2, &z1_plus_z2), &z1z1), &z2z2),
+        &h
+    );
+
+    [x3, y3, z3]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use p256::ecdsa::{
+        signature::{ hazmat::PrehashSigner, Signer },
+        Signature,
+        SigningKey,
+        VerifyingKey,
+    };
+
+    fn signature(seed: u8, message: &[u8]) -> Signature {
+        SigningKey::from_bytes(&[seed; 32].into()).unwrap().sign(message)
+    }
+
+    // SEQUENCE { INTEGER r, INTEGER s } from the integers' content bytes
+    fn encode(r: &[u8], s: &[u8]) -> ([u8; 80], usize) {
+        let mut der = [0u8; 80];
+        let len = 2 + r.len() + 2 + s.len();
+
+        der[..2].copy_from_slice(&[0x30, len as u8]);
+        der[2..4].copy_from_slice(&[0x02, r.len() as u8]);
+        der[4..4 + r.len()].copy_from_slice(r);
+        der[4 + r.len()..6 + r.len()].copy_from_slice(&[0x02, s.len() as u8]);
+        der[6 + r.len()..2 + len].copy_from_slice(s);
+
+        (der, 2 + len)
+    }
+
+    #[test]
+    fn der_matches_compact() {
+        for seed in 1..=16u8 {
+            let signature = signature(seed, &[seed; 8]);
+            let der = signature.to_der();
+            let compact = signature.normalize_s().unwrap_or(signature).to_bytes();
+
+            assert_eq!(normalize_signature(der.as_bytes()).unwrap()[..], compact[..]);
+            assert_eq!(normalize_signature(&signature.to_bytes()).unwrap()[..], compact[..]);
+        }
+    }
+
+    #[test]
+    fn known_der_vector() {
+        // r = 1 and s = n - 1, which is high and becomes 1
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let mut s = [0u8; 33];
+        s[1..].copy_from_slice(&sub(&ORDER, &one));
+        let (der, len) = encode(&[0x01], &s);
+
+        let mut expected = [0u8; 64];
+        expected[31] = 1;
+        expected[63] = 1;
+
+        assert_eq!(der[..6], [0x30, 0x26, 0x02, 0x01, 0x01, 0x02]);
+        assert_eq!(normalize_signature(&der[..len]), Some(expected));
+    }
+
+    #[test]
+    fn high_s_becomes_low_s() {
+        let signature = signature(7, b"message");
+        let low = signature.normalize_s().unwrap_or(signature).to_bytes();
+
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&low[32..]);
+        let mut high = [0u8; 64];
+        high[..32].copy_from_slice(&low[..32]);
+        high[32..].copy_from_slice(&sub(&ORDER, &s));
+
+        assert!(high[32..] > HALF_ORDER[..]);
+        assert_eq!(normalize_signature(&high).unwrap()[..], low[..]);
+        assert_eq!(normalize_signature(&low).unwrap()[..], low[..]);
+
+        // n / 2 itself is low
+        high[32..].copy_from_slice(&HALF_ORDER);
+        assert_eq!(normalize_signature(&high).unwrap()[32..], HALF_ORDER);
+    }
+
+    #[test]
+    fn leading_zero_padding() {
+        // The high bit needs a zero byte in front
+        let (der, len) = encode(&[0x00, 0x80], &[0x01]);
+        let compact = normalize_signature(&der[..len]).unwrap();
+        assert_eq!((compact[31], compact[63]), (0x80, 0x01));
+
+        // Without it the integer is negative
+        let (der, len) = encode(&[0x80], &[0x01]);
+        assert_eq!(normalize_signature(&der[..len]), None);
+
+        // A zero byte that isn't needed is not minimal DER
+        let (der, len) = encode(&[0x00, 0x7f], &[0x01]);
+        assert_eq!(normalize_signature(&der[..len]), None);
+
+        // 33 bytes with the zero byte is the longest integer, as long as it's below n
+        let mut r = [0u8; 33];
+        r[1] = 0x80;
+        let (der, len) = encode(&r, &[0x01]);
+        assert!(normalize_signature(&der[..len]).is_some());
+
+        r[1..].fill(0xff);
+        let (der, len) = encode(&r, &[0x01]);
+        assert_eq!(normalize_signature(&der[..len]), None);
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        let (mut der, len) = encode(&[0x01], &[0x01]);
+        assert!(normalize_signature(&der[..len]).is_some());
+
+        // Sequence length doesn't match the input
+        der[1] += 1;
+        assert_eq!(normalize_signature(&der[..len]), None);
+        der[1] -= 1;
+
+        // Integer length runs past the end
+        der[3] = 0x10;
+        assert_eq!(normalize_signature(&der[..len]), None);
+
+        // Integers longer than 33 bytes
+        let mut r = [0u8; 34];
+        r[2] = 0x80;
+        let (der, len) = encode(&r, &[0x01]);
+        assert_eq!(normalize_signature(&der[..len]), None);
+
+        // Zero length integer
+        let (der, len) = encode(&[], &[0x01]);
+        assert_eq!(normalize_signature(&der[..len]), None);
+
+        // Long form length
+        let long_form = [0x30, 0x81, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01];
+        assert_eq!(normalize_signature(&long_form), None);
+
+        // Longer than any P-256 signature
+        assert_eq!(normalize_signature(&[0x30; MAX_DER_SIGNATURE_LEN + 1]), None);
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let (mut der, len) = encode(&[0x01], &[0x01]);
+
+        // Inside the sequence
+        der[1] += 1;
+        assert_eq!(normalize_signature(&der[..len + 1]), None);
+
+        // After the sequence
+        der[1] -= 1;
+        assert_eq!(normalize_signature(&der[..len + 1]), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_scalars() {
+        let mut compact = [0u8; 64];
+        compact[63] = 1;
+        assert_eq!(normalize_signature(&compact), None); // r = 0
+
+        compact[..32].copy_from_slice(&ORDER);
+        assert_eq!(normalize_signature(&compact), None); // r = n
+
+        compact[31] = 0x50;
+        assert!(normalize_signature(&compact).is_some()); // r = n - 1
+    }
+
+    fn to_mont(value: &Fe) -> Fe {
+        mont_mul(value, &R2)
+    }
+
+    // P - 1, little endian limbs
+    fn p_minus(value: u64) -> Fe {
+        sub_raw(&P, &[value, 0, 0, 0]).0
+    }
+
+    #[test]
+    fn montgomery_round_trip() {
+        for value in [[0, 0, 0, 0], [1, 0, 0, 0], [u64::MAX, 7, 0, 1 << 63], p_minus(1)] {
+            assert_eq!(from_mont(&to_mont(&value)), value);
+        }
+
+        // Encoding stops at p
+        assert_eq!(fe_from_be(&fe_to_be(&P)), None);
+        let largest = fe_from_be(&fe_to_be(&p_minus(1))).unwrap();
+        assert_eq!(from_mont(&largest), p_minus(1));
+    }
+
+    #[test]
+    fn montgomery_arithmetic() {
+        // Small products don't wrap
+        let a = to_mont(&[0xffff_ffff, 0, 0, 0]);
+        let b = to_mont(&[0x1_0000_0001, 0, 0, 0]);
+        assert_eq!(from_mont(&mont_mul(&a, &b)), [0xffff_ffff_ffff_ffff, 0, 0, 0]);
+
+        // (p - 1)^2 = 1
+        let minus_one = to_mont(&p_minus(1));
+        assert_eq!(from_mont(&mont_mul(&minus_one, &minus_one)), [1, 0, 0, 0]);
+
+        // Additions and subtractions wrap around p
+        let one = to_mont(&[1, 0, 0, 0]);
+        assert_eq!(from_mont(&add_mod(&minus_one, &one)), [0, 0, 0, 0]);
+        assert_eq!(from_mont(&sub_mod(&[0; 4], &one)), p_minus(1));
+        assert_eq!(from_mont(&add_mod(&minus_one, &minus_one)), p_minus(2));
+
+        // Fermat: a^(p - 1) = 1
+        let a = to_mont(&[0x1234_5678, 0xdead_beef, 42, 7]);
+        assert_eq!(from_mont(&pow(&a, &p_minus(1))), [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn decompresses_both_parities() {
+        let mut parities = [false; 2];
+
+        for seed in 1..=32u8 {
+            let key = SigningKey::from_bytes(&[seed; 32].into()).unwrap();
+            let compressed = key.verifying_key().to_encoded_point(true);
+            let compressed = compressed.as_bytes();
+            let uncompressed = key.verifying_key().to_encoded_point(false);
+            let uncompressed = uncompressed.as_bytes();
+
+            assert_eq!(parse_public_key(compressed).unwrap()[..], uncompressed[..]);
+            assert_eq!(parse_public_key(uncompressed).unwrap()[..], uncompressed[..]);
+
+            parities[(compressed[0] & 1) as usize] = true;
+        }
+
+        assert_eq!(parities, [true, true]);
+    }
+
+    #[test]
+    fn wrong_parity_is_the_other_point() {
+        let key = SigningKey::from_bytes(&[3; 32].into()).unwrap();
+        let mut compressed = [0u8; 33];
+        compressed.copy_from_slice(key.verifying_key().to_encoded_point(true).as_bytes());
+        compressed[0] ^= 1;
+
+        let negated = parse_public_key(&compressed).unwrap();
+        assert!(VerifyingKey::from_sec1_bytes(&negated).is_ok());
+        assert_ne!(negated[..], key.verifying_key().to_encoded_point(false).as_bytes()[..]);
+    }
+
+    #[test]
+    fn rejects_x_not_on_the_curve() {
+        let mut tested = 0;
+
+        for x in 0..32u8 {
+            let mut compressed = [0u8; 33];
+            compressed[0] = 0x02;
+            compressed[32] = x;
+
+            if VerifyingKey::from_sec1_bytes(&compressed).is_err() {
+                assert_eq!(parse_public_key(&compressed), None);
+                tested += 1;
+            }
+        }
+
+        assert!(tested > 0);
+
+        // A valid x with a y that doesn't match
+        let key = SigningKey::from_bytes(&[3; 32].into()).unwrap();
+        let mut uncompressed = [0u8; 65];
+        uncompressed.copy_from_slice(key.verifying_key().to_encoded_point(false).as_bytes());
+        uncompressed[64] ^= 1;
+        assert_eq!(parse_public_key(&uncompressed), None);
+    }
+
+    #[test]
+    fn rejects_coordinates_above_p() {
+        let mut compressed = [0x02; 33];
+        compressed[1..].copy_from_slice(&fe_to_be(&P));
+        assert_eq!(parse_public_key(&compressed), None);
+
+        // y above p
+        let key = SigningKey::from_bytes(&[3; 32].into()).unwrap();
+        let mut uncompressed = [0u8; 65];
+        uncompressed.copy_from_slice(key.verifying_key().to_encoded_point(false).as_bytes());
+        uncompressed[33..].copy_from_slice(&[0xff; 32]);
+        assert_eq!(parse_public_key(&uncompressed), None);
+
+        compressed[1..].fill(0xff);
+        assert_eq!(parse_public_key(&compressed), None);
+    }
+
+    #[test]
+    fn rejects_zero_and_malformed_keys() {
+        assert_eq!(parse_public_key(&[0x00]), None); // SEC1 point at infinity
+        assert_eq!(parse_public_key(&[0u8; 65]), None);
+        assert_eq!(parse_public_key(&[0u8; 33]), None);
+        assert_eq!(parse_public_key(&[]), None);
+
+        let mut zero = [0u8; 65];
+        zero[0] = 0x04;
+        assert_eq!(parse_public_key(&zero), None);
+
+        let key = SigningKey::from_bytes(&[3; 32].into()).unwrap();
+        let compressed = key.verifying_key().to_encoded_point(true);
+        let mut wrong_prefix = [0u8; 33];
+        wrong_prefix.copy_from_slice(compressed.as_bytes());
+        wrong_prefix[0] = 0x04;
+        assert_eq!(parse_public_key(&wrong_prefix), None);
+        assert_eq!(parse_public_key(&compressed.as_bytes()[..32]), None);
+    }
+
+    fn signed_digest(seed: u8, digest: &[u8; 32]) -> ([u8; 65], [u8; 64]) {
+        let key = SigningKey::from_bytes(&[seed; 32].into()).unwrap();
+        let signature: Signature = key.sign_prehash(digest).unwrap();
+
+        let mut public_key = [0u8; 65];
+        public_key.copy_from_slice(key.verifying_key().to_encoded_point(false).as_bytes());
+
+        (public_key, normalize_signature(&signature.to_bytes()).unwrap())
+    }
+
+    #[test]
+    fn verifies_valid_signatures() {
+        for seed in 1..=8u8 {
+            let digest = [seed.wrapping_mul(37); 32];
+            let (public_key, signature) = signed_digest(seed, &digest);
+
+            assert!(verify(&public_key, &digest, &signature));
+        }
+
+        // A digest above n is reduced first
+        let (public_key, signature) = signed_digest(9, &[0xff; 32]);
+        assert!(verify(&public_key, &[0xff; 32], &signature));
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let digest = [7; 32];
+        let (public_key, signature) = signed_digest(1, &digest);
+        let (other_key, _) = signed_digest(2, &digest);
+
+        assert!(!verify(&other_key, &digest, &signature));
+        assert!(!verify(&public_key, &[8; 32], &signature));
+
+        for i in [0, 31, 32, 63] {
+            let mut tampered = signature;
+            tampered[i] ^= 1;
+            assert!(!verify(&public_key, &digest, &tampered));
+        }
+
+        // r and s must be scalars
+        let mut zero = signature;
+        zero[..32].fill(0);
+        assert!(!verify(&public_key, &digest, &zero));
+
+        let mut above = signature;
+        above[32..].copy_from_slice(&ORDER);
+        assert!(!verify(&public_key, &digest, &above));
+
+        // So must the key be a point
+        let mut off_curve = public_key;
+        off_curve[64] ^= 1;
+        assert!(!verify(&off_curve, &digest, &signature));
+    }
+}
@@ -1,21 +1,1769 @@
 #![cfg(test)]
+extern crate std;
 
 use super::*;
-use soroban_sdk::{vec, Env, String};
+use ed25519_dalek::Signer as _;
+use p256::ecdsa::{ Signature as P256Signature, SigningKey };
+use soroban_sdk::{
+    auth::ContractContext,
+    contract,
+    contractimpl,
+    testutils::{ Address as _, Events, Ledger, MockAuth, MockAuthInvoke },
+    token::StellarAssetClient,
+    vec,
+    xdr::{ self, WriteXdr },
+    InvokeError,
+    IntoVal,
+    Val,
+};
+use std::{ format, string::String };
+
+const RP_ID: &str = "numberspay.com";
+const ORIGIN: &str = "https://app.numberspay.com";
+const NOW: u64 = 1_700_000_000;
+const DAILY_LIMIT: i128 = 1000;
+
+// Flags byte of authenticator data
+const UP: u8 = 0x01;
+const UV: u8 = 0x04;
+
+// Everything an authenticator puts in an assertion, tests change single fields
+struct Assertion {
+    type_: &'static str,
+    origin: &'static str,
+    rp_id: &'static str,
+    flags: u8,
+    counter: u32,
+    challenge: Option<String>, // Defaults to the base64url encoded payload
+    extra: &'static str, // Appended to the clientDataJSON members
+}
+
+fn assertion(counter: u32) -> Assertion {
+    Assertion {
+        type_: "webauthn.get",
+        origin: ORIGIN,
+        rp_id: RP_ID,
+        flags: UP | UV,
+        counter,
+        challenge: None,
+        extra: "",
+    }
+}
+
+// A device holding one P-256 passkey
+struct Authenticator {
+    id: Bytes,
+    key: SigningKey,
+    counter: u32,
+}
+
+impl Authenticator {
+    fn new(env: &Env, seed: u8) -> Self {
+        Authenticator {
+            id: Bytes::from_array(env, &[seed; 16]),
+            key: SigningKey::from_bytes(&[seed; 32].into()).unwrap(),
+            counter: 0,
+        }
+    }
+
+    fn public_key(&self, env: &Env) -> Bytes {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        Bytes::from_slice(env, point.as_bytes())
+    }
+
+    // A valid assertion with a fresh counter
+    fn sign(&mut self, env: &Env, payload: &[u8; 32]) -> WebAuthnSignature {
+        self.counter += 1;
+        self.sign_with(env, payload, &assertion(self.counter))
+    }
+
+    fn sign_with(&self, env: &Env, payload: &[u8; 32], assertion: &Assertion) -> WebAuthnSignature {
+        let challenge = assertion.challenge.clone().unwrap_or_else(|| {
+            let mut encoded = [0u8; 43];
+            base64_urls::encode(&mut encoded, payload);
+            String::from_utf8(encoded.to_vec()).unwrap()
+        });
+
+        let client_data_json = format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}"{}}}"#,
+            assertion.type_,
+            challenge,
+            assertion.origin,
+            assertion.extra
+        );
+        let client_data_json = Bytes::from_slice(env, client_data_json.as_bytes());
+
+        let rp_id_hash = env.crypto().sha256(&Bytes::from_slice(env, assertion.rp_id.as_bytes()));
+        let mut authenticator_data = Bytes::from_array(env, &rp_id_hash.to_array());
+        authenticator_data.push_back(assertion.flags);
+        authenticator_data.extend_from_array(&assertion.counter.to_be_bytes());
+
+        // The authenticator signs authenticator_data || sha256(clientDataJSON)
+        let mut message = authenticator_data.clone();
+        message.extend_from_array(&env.crypto().sha256(&client_data_json).to_array());
+
+        let mut buffer = [0u8; 256];
+        let message_len = message.len() as usize;
+        message.copy_into_slice(&mut buffer[..message_len]);
+        let signature: P256Signature = self.key.sign(&buffer[..message_len]);
+
+        WebAuthnSignature {
+            id: self.id.clone(),
+            authenticator_data,
+            client_data_json,
+            signature: Bytes::from_slice(env, &signature.to_bytes()),
+        }
+    }
+}
+
+// Approves every policy and values tokens 1:1 in the base currency
+#[contract]
+struct MockUserManager;
+
+#[contractimpl]
+impl MockUserManager {
+    pub fn is_policy_approved(_env: Env, _policy: Address) -> bool {
+        true
+    }
+
+    pub fn value_of(_env: Env, _token: Address, amount: i128) -> Result<i128, SdkError> {
+        Ok(amount)
+    }
+}
+
+#[contract]
+struct RejectAll;
+
+#[contractimpl]
+impl RejectAll {
+    pub fn check(
+        _env: Env,
+        _wallet: Address,
+        _contexts: Vec<Context>,
+        _signers: Vec<Signer>
+    ) -> Result<(), SdkError> {
+        Err(SdkError::from_contract_error(1))
+    }
+}
+
+fn ed25519_key(seed: u8) -> ed25519_dalek::SigningKey {
+    ed25519_dalek::SigningKey::from_bytes(&[seed; 32])
+}
+
+fn ed25519_signer(env: &Env, key: &ed25519_dalek::SigningKey) -> Signer {
+    Signer::Ed25519(BytesN::from_array(env, &key.verifying_key().to_bytes()))
+}
+
+fn ed25519_sign(
+    env: &Env,
+    key: &ed25519_dalek::SigningKey,
+    payload: &[u8; 32]
+) -> SignerSignature {
+    SignerSignature::Ed25519(Ed25519Signature {
+        public_key: BytesN::from_array(env, &key.verifying_key().to_bytes()),
+        signature: BytesN::from_array(env, &key.sign(payload).to_bytes()),
+    })
+}
+
+// Authorization entry for the wallet, signed by an Ed25519 signer like a wallet backend would
+fn ed25519_authorize(
+    env: &Env,
+    wallet: &Address,
+    key: &ed25519_dalek::SigningKey,
+    invoke: &MockAuthInvoke
+) -> xdr::SorobanAuthorizationEntry {
+    let nonce = 1;
+    let signature_expiration_ledger = env.ledger().sequence() + 100;
+    let invocation: xdr::SorobanAuthorizedInvocation = invoke.into();
+
+    let preimage = xdr::HashIdPreimage::SorobanAuthorization(
+        xdr::HashIdPreimageSorobanAuthorization {
+            network_id: xdr::Hash(env.ledger().network_id().to_array()),
+            nonce,
+            signature_expiration_ledger,
+            invocation: invocation.clone(),
+        }
+    );
+    let preimage = preimage.to_xdr(xdr::Limits::none()).unwrap();
+    let payload = env.crypto().sha256(&Bytes::from_slice(env, &preimage)).to_array();
+
+    let signatures = vec![env, ed25519_sign(env, key, &payload)];
+    xdr::SorobanAuthorizationEntry {
+        credentials: xdr::SorobanCredentials::Address(xdr::SorobanAddressCredentials {
+            address: wallet.into(),
+            nonce,
+            signature_expiration_ledger,
+            signature: xdr::ScVal::try_from_val(env, &signatures.to_val()).unwrap(),
+        }),
+        root_invocation: invocation,
+    }
+}
+
+fn call(env: &Env, contract: &Address, fn_name: &str, args: Vec<Val>) -> Context {
+    Context::Contract(ContractContext {
+        contract: contract.clone(),
+        fn_name: Symbol::new(env, fn_name),
+        args,
+    })
+}
+
+fn send_call(env: &Env, wallet: &Address, to: &Address, token: &Address, amount: i128) -> Context {
+    let args = vec![env, to.into_val(env), token.into_val(env), amount.into_val(env)];
+    call(env, wallet, "send", args)
+}
+
+fn check_auth(
+    env: &Env,
+    wallet: &Address,
+    payload: &[u8; 32],
+    signatures: Vec<SignerSignature>,
+    contexts: Vec<Context>
+) -> Result<(), Result<SdkError, InvokeError>> {
+    env.try_invoke_contract_check_auth::<SdkError>(
+        wallet,
+        &BytesN::from_array(env, payload),
+        signatures.into_val(env),
+        &contexts
+    )
+}
+
+fn contract_error(code: u32) -> Result<(), Result<SdkError, InvokeError>> {
+    Err(Ok(SdkError::from_contract_error(code)))
+}
+
+fn approval_payload(env: &Env, wallet: &Address, id: u32) -> [u8; 32] {
+    let payload = (symbol_short!("approve"), wallet.clone(), id);
+    env.crypto().sha256(&payload.to_xdr(env)).to_array()
+}
+
+fn setup(env: &Env) -> (NBSWalletClient<'_>, Authenticator, Address) {
+    setup_with(env, None)
+}
+
+fn setup_with(
+    env: &Env,
+    user_manager: Option<Address>
+) -> (NBSWalletClient<'_>, Authenticator, Address) {
+    env.mock_all_auths();
+    env.ledger().set_timestamp(NOW);
+
+    let wallet = NBSWalletClient::new(env, &env.register(NBSWallet, ()));
+    let device = Authenticator::new(env, 1);
+
+    let admin = Address::generate(env);
+    let token = env.register_stellar_asset_contract_v2(admin).address();
+    StellarAssetClient::new(env, &token).mint(&wallet.address, &10_000);
+
+    let mut limits = Map::new(env);
+    limits.set(token.clone(), SpendingLimits {
+        daily: DAILY_LIMIT,
+        weekly: None,
+        monthly: None,
+    });
+
+    wallet.initialize(
+        &device.id,
+        &device.public_key(env),
+        &limits,
+        &vec![env, Bytes::from_slice(env, ORIGIN.as_bytes())],
+        &Bytes::from_slice(env, RP_ID.as_bytes()),
+        &None,
+        &user_manager
+    );
+
+    (wallet, device, token)
+}
+
+#[test]
+fn passkey_assertion_authorizes() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let payload = [7; 32];
+    let to = Address::generate(&env);
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+
+    let result = check_auth(&env, &wallet.address, &payload, vec![&env, signature], contexts);
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+fn challenge_is_bound_to_the_signature_payload() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    // Signed for another invocation
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], contexts),
+        contract_error(ERROR_CHALLENGE_MISMATCH)
+    );
+}
+
+#[test]
+fn challenge_must_be_unpadded_base64url() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+
+    // Encodes to "-_v7..." in base64url and "+/v7..." in standard base64
+    let payload = [0xfb; 32];
+    let mut encoded = [0u8; 43];
+    base64_urls::encode(&mut encoded, &payload);
+    let encoded = String::from_utf8(encoded.to_vec()).unwrap();
+
+    let mut standard = assertion(1);
+    standard.challenge = Some(encoded.replace('-', "+").replace('_', "/"));
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &standard));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_CHALLENGE_MISMATCH)
+    );
+
+    let mut padded = assertion(2);
+    padded.challenge = Some(format!("{}=", encoded));
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &padded));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_CHALLENGE_MISMATCH)
+    );
+}
+
+#[test]
+fn registration_data_is_not_an_assertion() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let payload = [7; 32];
+
+    let mut create = assertion(1);
+    create.type_ = "webauthn.create";
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &create));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_INVALID_CLIENT_DATA)
+    );
+}
+
+#[test]
+fn duplicate_challenge_is_rejected() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let payload = [7; 32];
+
+    // A parser keeping the last value would read the attacker's challenge
+    let mut duplicate = assertion(1);
+    duplicate.extra = r#","challenge":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA""#;
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &duplicate));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_INVALID_CLIENT_DATA)
+    );
+}
+
+#[test]
+fn assertion_from_other_origin_is_rejected() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let payload = [7; 32];
+
+    let mut other = assertion(1);
+    other.origin = "https://numberspay.example.com";
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &other));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_ORIGIN_NOT_ALLOWED)
+    );
+}
 
 #[test]
-fn test() {
+fn origins_can_be_added_and_removed() {
     let env = Env::default();
-    let contract_id = env.register(Contract, ());
-    let client = ContractClient::new(&env, &contract_id);
+    let (wallet, mut device, _) = setup(&env);
+    let other_origin = "https://pay.numberspay.com";
+    let origin = Bytes::from_slice(&env, ORIGIN.as_bytes());
+    let other = Bytes::from_slice(&env, other_origin.as_bytes());
+
+    wallet.add_origin(&other);
+    assert_eq!(
+        env.events().all(),
+        vec![
+            &env,
+            (
+                wallet.address.clone(),
+                (EVENT_TAG, symbol_short!("orig_add")).into_val(&env),
+                other.into_val(&env),
+            )
+        ]
+    );
+
+    let mut from_other = assertion(1);
+    from_other.origin = other_origin;
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[1; 32], &from_other));
+    let result = check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], vec![&env]);
+    assert_eq!(result, Ok(()));
 
-    let words = client.hello(&String::from_str(&env, "Dev"));
+    wallet.remove_origin(&origin);
     assert_eq!(
-        words,
+        env.events().all(),
         vec![
             &env,
-            String::from_str(&env, "Hello"),
-            String::from_str(&env, "Dev"),
+            (
+                wallet.address.clone(),
+                (EVENT_TAG, symbol_short!("orig_rm")).into_val(&env),
+                origin.into_val(&env),
+            )
         ]
     );
+
+    // Assertions from the removed origin are rejected
+    let signature = SignerSignature::Passkey(device.sign(&env, &[2; 32]));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], vec![&env]),
+        contract_error(ERROR_ORIGIN_NOT_ALLOWED)
+    );
+    assert_eq!(
+        wallet.try_remove_origin(&origin),
+        Err(Ok(SdkError::from_contract_error(ERROR_ORIGIN_NOT_ALLOWED)))
+    );
+
+    // The wallet keeps at least one origin
+    assert_eq!(
+        wallet.try_remove_origin(&other),
+        Err(Ok(SdkError::from_contract_error(ERROR_NO_ORIGINS)))
+    );
+}
+
+#[test]
+fn cross_origin_assertion_is_rejected() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+
+    let mut embedded = assertion(1);
+    embedded.extra = r#","crossOrigin":true"#;
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[1; 32], &embedded));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], vec![&env]),
+        contract_error(ERROR_ORIGIN_NOT_ALLOWED)
+    );
+
+    let mut top_level = assertion(2);
+    top_level.extra = r#","crossOrigin":false"#;
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[2; 32], &top_level));
+    let result = check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], vec![&env]);
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+fn assertion_for_other_rp_id_is_rejected() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let payload = [7; 32];
+
+    let mut other = assertion(1);
+    other.rp_id = "numberspay.example.com";
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &other));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_RP_ID_MISMATCH)
+    );
+}
+
+#[test]
+fn user_presence_is_required() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let payload = [7; 32];
+
+    let mut not_present = assertion(1);
+    not_present.flags = UV;
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &not_present));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_INVALID_SIGNATURE)
+    );
+}
+
+#[test]
+fn user_verification_follows_the_policy() {
+    let env = Env::default();
+    let (wallet, device, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let mut present_only = assertion(1);
+    present_only.flags = UP;
+
+    // Always required by default
+    let small = vec![&env, send_call(&env, &wallet.address, &to, &token, 100)];
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[1; 32], &present_only));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], small.clone()),
+        contract_error(ERROR_USER_VERIFICATION_REQUIRED)
+    );
+
+    wallet.set_user_verification(&UserVerification::AboveAmount(500));
+
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[1; 32], &present_only));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], small),
+        Ok(())
+    );
+
+    present_only.counter = 2;
+    let large = vec![&env, send_call(&env, &wallet.address, &to, &token, 600)];
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[2; 32], &present_only));
+
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], large),
+        contract_error(ERROR_USER_VERIFICATION_REQUIRED)
+    );
+}
+
+#[test]
+fn sign_count_must_increase() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[1; 32], &assertion(5)));
+    let result = check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], vec![&env]);
+    assert_eq!(result, Ok(()));
+
+    // Same counter again, e.g. from a cloned authenticator
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[2; 32], &assertion(5)));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], vec![&env]),
+        contract_error(ERROR_SIGN_COUNT_REPLAY)
+    );
+
+    // Once a counter was seen, reporting 0 is a replay too
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[3; 32], &assertion(0)));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[3; 32], vec![&env, signature], vec![&env]),
+        contract_error(ERROR_SIGN_COUNT_REPLAY)
+    );
+
+    let signature = SignerSignature::Passkey(device.sign_with(&env, &[4; 32], &assertion(6)));
+    let result = check_auth(&env, &wallet.address, &[4; 32], vec![&env, signature], vec![&env]);
+    assert_eq!(result, Ok(()));
+    assert_eq!(wallet.list_passkeys().get(0).unwrap().sign_count, 6);
+}
+
+#[test]
+fn zero_sign_count_keeps_working() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+
+    // Synced passkeys always report 0
+    for payload in [[1; 32], [2; 32]] {
+        let signature = SignerSignature::Passkey(device.sign_with(&env, &payload, &assertion(0)));
+        assert_eq!(
+            check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+            Ok(())
+        );
+    }
+}
+
+#[test]
+fn last_passkey_cannot_be_removed() {
+    let env = Env::default();
+    let (wallet, mut device, _) = setup(&env);
+    let mut phone = Authenticator::new(&env, 2);
+
+    assert_eq!(
+        wallet.try_remove_passkey(&device.id),
+        Err(Ok(SdkError::from_contract_error(ERROR_LAST_PASSKEY)))
+    );
+    assert_eq!(
+        wallet.try_remove_passkey(&phone.id),
+        Err(Ok(SdkError::from_contract_error(ERROR_PASSKEY_NOT_FOUND)))
+    );
+
+    wallet.add_passkey(&phone.id, &phone.public_key(&env));
+    wallet.remove_passkey(&device.id);
+    assert_eq!(wallet.list_passkeys().len(), 1);
+
+    // The removed passkey no longer signs, the remaining one does
+    let payload = [7; 32];
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_PASSKEY_NOT_FOUND)
+    );
+    let signatures = vec![&env, SignerSignature::Passkey(phone.sign(&env, &payload))];
+    assert_eq!(check_auth(&env, &wallet.address, &payload, signatures, vec![&env]), Ok(()));
+}
+
+#[test]
+fn updated_passkey_replaces_the_old_one() {
+    let env = Env::default();
+    let (wallet, mut device, _) = setup(&env);
+    let mut phone = Authenticator::new(&env, 2);
+
+    // Migrating the only passkey to a new device
+    wallet.update_passkey(&device.id, &phone.id, &phone.public_key(&env));
+    assert_eq!(wallet.list_passkeys().get(0).unwrap().id, phone.id);
+    assert_eq!(
+        wallet.try_update_passkey(&phone.id, &phone.id, &phone.public_key(&env)),
+        Err(Ok(SdkError::from_contract_error(ERROR_PASSKEY_EXISTS)))
+    );
+
+    let payload = [7; 32];
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], vec![&env]),
+        contract_error(ERROR_PASSKEY_NOT_FOUND)
+    );
+    let signatures = vec![&env, SignerSignature::Passkey(phone.sign(&env, &payload))];
+    assert_eq!(check_auth(&env, &wallet.address, &payload, signatures, vec![&env]), Ok(()));
+}
+
+fn login_challenge(env: &Env, hash: &[u8; 32]) -> [u8; 32] {
+    let mut message = Bytes::from_slice(env, b"nbswallet:login");
+    message.extend_from_array(hash);
+    env.crypto().sha256(&message).to_array()
+}
+
+#[test]
+fn verify_message_checks_a_login_assertion() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let hash = [9; 32];
+    let challenge = login_challenge(&env, &hash);
+
+    let signature = device.sign_with(&env, &challenge, &assertion(1));
+    assert!(wallet.verify_message(&BytesN::from_array(&env, &hash), &signature));
+
+    // The same assertion isn't valid for another hash
+    assert!(!wallet.verify_message(&BytesN::from_array(&env, &[8; 32]), &signature));
+
+    // Unknown passkeys are rejected
+    let stranger = Authenticator::new(&env, 4).sign_with(&env, &challenge, &assertion(1));
+    assert!(!wallet.verify_message(&BytesN::from_array(&env, &hash), &stranger));
+}
+
+#[test]
+fn verify_message_returns_false_for_a_bad_signature() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let hash = [9; 32];
+
+    let mut signature = device.sign_with(&env, &login_challenge(&env, &hash), &assertion(1));
+    let last = signature.signature.len() - 1;
+    signature.signature.set(last, signature.signature.get(last).unwrap() ^ 1);
+
+    assert!(!wallet.verify_message(&BytesN::from_array(&env, &hash), &signature));
+}
+
+#[test]
+fn login_and_transaction_assertions_are_separate() {
+    let env = Env::default();
+    let (wallet, device, _) = setup(&env);
+    let hash = [9; 32];
+
+    // Signed as a transaction payload
+    let signature = device.sign_with(&env, &hash, &assertion(1));
+    assert!(!wallet.verify_message(&BytesN::from_array(&env, &hash), &signature));
+
+    // Signed as a login, replayed as authorization
+    let signature = device.sign_with(&env, &login_challenge(&env, &hash), &assertion(1));
+    let signatures = vec![&env, SignerSignature::Passkey(signature)];
+    assert_eq!(
+        check_auth(&env, &wallet.address, &hash, signatures, vec![&env]),
+        contract_error(ERROR_CHALLENGE_MISMATCH)
+    );
+}
+
+#[test]
+fn threshold_needs_distinct_signers() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+    let backend = ed25519_key(9);
+
+    wallet.add_signer(&ed25519_signer(&env, &backend));
+    wallet.set_threshold(&ThresholdPolicy { threshold: 2, functions: Map::new(&env) });
+
+    let payload = [7; 32];
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], contexts.clone()),
+        contract_error(ERROR_THRESHOLD_NOT_MET)
+    );
+
+    let backend_signature = ed25519_sign(&env, &backend, &payload);
+    let twice = vec![&env, backend_signature.clone(), backend_signature];
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, twice, contexts.clone()),
+        contract_error(ERROR_DUPLICATE_SIGNATURE)
+    );
+
+    let signatures = vec![
+        &env,
+        SignerSignature::Passkey(device.sign(&env, &payload)),
+        ed25519_sign(&env, &backend, &payload)
+    ];
+    assert_eq!(check_auth(&env, &wallet.address, &payload, signatures, contexts), Ok(()));
+}
+
+#[test]
+fn account_signer_authorizes_through_its_own_auth() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let hardware = Address::generate(&env);
+
+    wallet.add_signer(&Signer::Account(hardware.clone()));
+
+    let payload = [7; 32];
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+    let signatures = vec![&env, SignerSignature::Account(hardware.clone())];
+
+    // The account has to authorize exactly this payload for the wallet
+    let authorize = |payload: [u8; 32]| {
+        env.mock_auths(
+            &[
+                MockAuth {
+                    address: &hardware,
+                    invoke: &(MockAuthInvoke {
+                        contract: &wallet.address,
+                        fn_name: "__check_auth",
+                        args: (BytesN::from_array(&env, &payload),).into_val(&env),
+                        sub_invokes: &[],
+                    }),
+                },
+            ]
+        );
+    };
+
+    authorize([8; 32]);
+    assert!(
+        check_auth(&env, &wallet.address, &payload, signatures.clone(), contexts.clone()).is_err()
+    );
+
+    authorize(payload);
+    assert_eq!(check_auth(&env, &wallet.address, &payload, signatures, contexts), Ok(()));
+
+    // Removed accounts no longer sign
+    env.mock_all_auths();
+    wallet.remove_signer(&Signer::Account(hardware.clone()));
+    authorize(payload);
+    assert_eq!(
+        check_auth(
+            &env,
+            &wallet.address,
+            &payload,
+            vec![&env, SignerSignature::Account(hardware.clone())],
+            vec![&env, send_call(&env, &wallet.address, &to, &token, 10)]
+        ),
+        contract_error(ERROR_SIGNER_NOT_FOUND)
+    );
+}
+
+#[test]
+fn threshold_can_differ_per_function() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    wallet.add_signer(&ed25519_signer(&env, &ed25519_key(9)));
+
+    let mut functions = Map::new(&env);
+    functions.set(symbol_short!("withdraw"), 2);
+    wallet.set_threshold(&ThresholdPolicy { threshold: 1, functions });
+
+    let payload = [7; 32];
+    let send = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    assert_eq!(check_auth(&env, &wallet.address, &payload, vec![&env, signature], send), Ok(()));
+
+    let withdraw = vec![
+        &env,
+        call(
+            &env,
+            &wallet.address,
+            "withdraw",
+            vec![&env, token.into_val(&env), (10i128).into_val(&env), to.into_val(&env)]
+        )
+    ];
+    let signature = SignerSignature::Passkey(device.sign(&env, &payload));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &payload, vec![&env, signature], withdraw),
+        contract_error(ERROR_THRESHOLD_NOT_MET)
+    );
+}
+
+#[test]
+fn unreachable_threshold_is_rejected() {
+    let env = Env::default();
+    let (wallet, _, _) = setup(&env);
+
+    assert_eq!(
+        wallet.try_set_threshold(&ThresholdPolicy { threshold: 2, functions: Map::new(&env) }),
+        Err(Ok(SdkError::from_contract_error(ERROR_INVALID_THRESHOLD)))
+    );
+}
+
+#[test]
+fn restricted_signer_stays_within_its_permissions() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let backend = ed25519_key(9);
+    let signer = ed25519_signer(&env, &backend);
+
+    wallet.add_signer(&signer);
+
+    let mut amount_caps = Map::new(&env);
+    amount_caps.set(token.clone(), 100);
+    wallet.set_permissions(
+        &signer,
+        &Some(SignerPermissions {
+            contracts: None,
+            functions: Some(vec![&env, symbol_short!("send")]),
+            amount_caps,
+        })
+    );
+
+    let payload = [7; 32];
+    let signatures = vec![&env, ed25519_sign(&env, &backend, &payload)];
+    let check = |contexts: Vec<Context>| {
+        check_auth(&env, &wallet.address, &payload, signatures.clone(), contexts)
+    };
+
+    assert_eq!(check(vec![&env, send_call(&env, &wallet.address, &to, &token, 100)]), Ok(()));
+    assert_eq!(
+        check(vec![&env, send_call(&env, &wallet.address, &to, &token, 101)]),
+        contract_error(ERROR_PERMISSION_DENIED)
+    );
+
+    // Settings and signer management aren't listed
+    let add_signer = call(&env, &wallet.address, "add_signer", vec![&env, signer.into_val(&env)]);
+    assert_eq!(check(vec![&env, add_signer]), contract_error(ERROR_PERMISSION_DENIED));
+}
+
+#[test]
+fn session_key_spends_its_budget() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let session = ed25519_key(5);
+    let public_key = BytesN::from_array(&env, &session.verifying_key().to_bytes());
+
+    let mut budgets = Map::new(&env);
+    budgets.set(token.clone(), 100);
+    let functions = vec![&env, symbol_short!("send")];
+    wallet.add_session_key(&public_key, &1000, &budgets, &functions);
+
+    let session_sign = |payload: &[u8; 32]| match ed25519_sign(&env, &session, payload) {
+        SignerSignature::Ed25519(signature) => SignerSignature::Session(signature),
+        _ => unreachable!(),
+    };
+
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 60)];
+    let signatures = vec![&env, session_sign(&[1; 32])];
+    assert_eq!(check_auth(&env, &wallet.address, &[1; 32], signatures, contexts), Ok(()));
+
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 60)];
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, session_sign(&[2; 32])], contexts),
+        contract_error(ERROR_SESSION_BUDGET_EXCEEDED)
+    );
+
+    // Adding the key again can't refill the budget
+    assert_eq!(
+        wallet.try_add_session_key(&public_key, &1000, &budgets, &functions),
+        Err(Ok(SdkError::from_contract_error(ERROR_SESSION_EXISTS)))
+    );
+    assert_eq!(wallet.get_session_key(&public_key).unwrap().budgets.get(token).unwrap(), 40);
+}
+
+#[test]
+fn session_key_is_limited_to_payments() {
+    let env = Env::default();
+    let (wallet, _, _) = setup(&env);
+    let public_key = BytesN::from_array(&env, &[5; 32]);
+
+    for function in ["add_signer", "set_threshold", "remove_policy", "add_session_key"] {
+        assert_eq!(
+            wallet.try_add_session_key(
+                &public_key,
+                &1000,
+                &Map::new(&env),
+                &vec![&env, Symbol::new(&env, function)]
+            ),
+            Err(Ok(SdkError::from_contract_error(ERROR_PERMISSION_DENIED)))
+        );
+    }
+}
+
+#[test]
+fn rejecting_policy_can_be_removed() {
+    let env = Env::default();
+    let user_manager = env.register(MockUserManager, ());
+    let (wallet, mut device, token) = setup_with(&env, Some(user_manager));
+    let to = Address::generate(&env);
+    let policy = env.register(RejectAll, ());
+
+    wallet.add_policy(&policy);
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], contexts),
+        contract_error(ERROR_POLICY_REJECTED)
+    );
+
+    // The policy isn't asked about its own removal
+    let remove = call(&env, &wallet.address, "remove_policy", vec![&env, policy.into_val(&env)]);
+    let signatures = vec![&env, SignerSignature::Passkey(device.sign(&env, &[2; 32]))];
+    let result = check_auth(&env, &wallet.address, &[2; 32], signatures, vec![&env, remove]);
+    assert_eq!(result, Ok(()));
+
+    // Nor can the removal be bundled with a payment
+    let remove = call(&env, &wallet.address, "remove_policy", vec![&env, policy.into_val(&env)]);
+    let send = send_call(&env, &wallet.address, &to, &token, 10);
+    let signatures = vec![&env, SignerSignature::Passkey(device.sign(&env, &[3; 32]))];
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[3; 32], signatures, vec![&env, remove, send]),
+        contract_error(ERROR_POLICY_REJECTED)
+    );
+
+    wallet.remove_policy(&policy);
+    assert_eq!(wallet.list_policies(), vec![&env, policy.clone()]);
+    assert_eq!(wallet.get_policy_removals().get(policy).unwrap(), NOW + SECONDS_PER_DAY);
+
+    // Time locked by the limit delay
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    assert_eq!(wallet.list_policies().len(), 0);
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &[4; 32]));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+    let result = check_auth(&env, &wallet.address, &[4; 32], vec![&env, signature], contexts);
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+fn policy_removal_can_be_cancelled() {
+    let env = Env::default();
+    let user_manager = env.register(MockUserManager, ());
+    let (wallet, _, _) = setup_with(&env, Some(user_manager));
+    let policy = env.register(RejectAll, ());
+
+    wallet.add_policy(&policy);
+    wallet.remove_policy(&policy);
+    wallet.cancel_policy_removal(&policy);
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    assert_eq!(wallet.list_policies(), vec![&env, policy.clone()]);
+    assert_eq!(
+        wallet.try_cancel_policy_removal(&policy),
+        Err(Ok(SdkError::from_contract_error(ERROR_NO_PENDING_CHANGE)))
+    );
+}
+
+#[test]
+fn direct_token_transfers_count_against_the_daily_limit() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let transfer = |amount: i128| {
+        vec![
+            &env,
+            call(
+                &env,
+                &token,
+                "transfer",
+                vec![&env, wallet.address.into_val(&env), to.into_val(&env), amount.into_val(&env)]
+            )
+        ]
+    };
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    let result = check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], transfer(800));
+    assert_eq!(result, Ok(()));
+    assert_eq!(wallet.get_daily_spending(&token).spent, 800);
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &[2; 32]));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[2; 32], vec![&env, signature], transfer(201)),
+        contract_error(ERROR_DAILY_LIMIT_EXCEEDED)
+    );
+}
+
+#[test]
+fn daily_limit_rolls_over_24_hours() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    // Half past the hour, so the next day's bucket starts before 24 hours have passed
+    let spent_at = (NOW / SECONDS_PER_HOUR) * SECONDS_PER_HOUR + SECONDS_PER_HOUR / 2;
+    env.ledger().set_timestamp(spent_at);
+    wallet.send(&to, &token, &600);
+
+    assert_eq!(
+        wallet.try_send(&to, &token, &401),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+
+    let status = wallet.get_daily_spending(&token);
+    assert_eq!((status.spent, status.remaining), (600, 400));
+
+    // Still within the window a second short of 24 hours later, even across midnight
+    env.ledger().set_timestamp(spent_at + 24 * SECONDS_PER_HOUR - 1);
+    assert!(wallet.try_send(&to, &token, &401).is_err());
+
+    // The hour of the payment drops out once it's fully over 24 hours ago
+    env.ledger().set_timestamp(spent_at + 24 * SECONDS_PER_HOUR + SECONDS_PER_HOUR / 2);
+    wallet.send(&to, &token, &1000);
+}
+
+#[test]
+fn spending_window_change_waits_for_the_delay() {
+    let env = Env::default();
+    let (wallet, _, _) = setup(&env);
+
+    wallet.set_spending_window(&SpendingWindow::Calendar(0));
+    assert_eq!(wallet.get_spending_window(), SpendingWindow::Rolling);
+    assert_eq!(wallet.get_pending_spending_window().unwrap().effective_at, NOW + SECONDS_PER_DAY);
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    assert_eq!(wallet.get_spending_window(), SpendingWindow::Calendar(0));
+
+    // Back to rolling is never a relaxation
+    wallet.set_spending_window(&SpendingWindow::Rolling);
+    assert_eq!(wallet.get_spending_window(), SpendingWindow::Rolling);
+    assert_eq!(wallet.get_pending_spending_window(), None);
+}
+
+#[test]
+fn switching_window_keeps_todays_spending() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    // Without a delay every switch applies right away
+    wallet.set_limit_delay(&0);
+    let noon = (NOW / SECONDS_PER_DAY + 2) * SECONDS_PER_DAY + 12 * SECONDS_PER_HOUR;
+    env.ledger().set_timestamp(noon);
+
+    wallet.set_spending_window(&SpendingWindow::Calendar(0));
+    wallet.send(&to, &token, &600);
+
+    let windows = [
+        SpendingWindow::Calendar(3 * 60 * 60),
+        SpendingWindow::Rolling,
+        SpendingWindow::Calendar(-10 * 60 * 60),
+        SpendingWindow::Calendar(11 * 60 * 60 + 30 * 60),
+    ];
+
+    for window in windows {
+        env.ledger().set_timestamp(env.ledger().timestamp() + 60);
+        wallet.set_spending_window(&window);
+
+        assert_eq!(wallet.get_spending_window(), window);
+        assert_eq!(wallet.get_daily_spending(&token).spent, 600);
+        assert_eq!(
+            wallet.try_send(&to, &token, &401),
+            Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+        );
+    }
+}
+
+#[test]
+fn calendar_day_resets_at_local_midnight() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    // 23:00 in UTC+2
+    let day = (NOW / SECONDS_PER_DAY + 2) * SECONDS_PER_DAY;
+    wallet.set_spending_window(&SpendingWindow::Calendar(2 * 60 * 60));
+    env.ledger().set_timestamp(day + 21 * SECONDS_PER_HOUR);
+
+    wallet.send(&to, &token, &1000);
+
+    env.ledger().set_timestamp(day + 22 * SECONDS_PER_HOUR);
+    assert_eq!(wallet.get_daily_spending(&token).spent, 0);
+    wallet.send(&to, &token, &1000);
+}
+
+#[test]
+fn weekly_cap_spans_days() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    wallet.set_limits(
+        &LimitScope::Token(token.clone()),
+        &SpendingLimits { daily: DAILY_LIMIT, weekly: Some(1500), monthly: None }
+    );
+
+    wallet.send(&to, &token, &1000);
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY + SECONDS_PER_HOUR);
+
+    assert_eq!(
+        wallet.try_send(&to, &token, &600),
+        Err(Ok(SdkError::from_contract_error(ERROR_WEEKLY_LIMIT_EXCEEDED)))
+    );
+    wallet.send(&to, &token, &500);
+
+    let status = wallet.get_limits_status(&token);
+    assert_eq!(status.get(1).unwrap().remaining, 0);
+}
+
+#[test]
+fn base_currency_limit_caps_token_spending() {
+    let env = Env::default();
+    let (wallet, _, token) = setup_with(&env, Some(env.register(MockUserManager, ())));
+    let to = Address::generate(&env);
+
+    wallet.set_limits(
+        &LimitScope::Base,
+        &SpendingLimits { daily: 600, weekly: None, monthly: None }
+    );
+
+    wallet.send(&to, &token, &400);
+    assert_eq!(
+        wallet.try_send(&to, &token, &300),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+
+    let status = wallet.get_base_limits_status();
+    assert_eq!(status.get(0).unwrap().spent, 400);
+    assert_eq!(status.get(0).unwrap().remaining, 200);
+}
+
+#[test]
+fn limit_increases_wait_for_the_delay() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let scope = LimitScope::Token(token.clone());
+
+    wallet.set_limits(&scope, &SpendingLimits { daily: 5000, weekly: None, monthly: None });
+    assert!(wallet.try_send(&to, &token, &2000).is_err());
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    wallet.send(&to, &token, &2000);
+}
+
+#[test]
+fn contact_is_trusted_once_the_delay_passed() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let friend = Address::generate(&env);
+    let mut limits = Map::new(&env);
+    limits.set(token.clone(), 2000);
+
+    wallet.add_contact(&friend, &BytesN::from_array(&env, &[1; 32]), &limits);
+    assert_eq!(
+        wallet.try_add_contact(&friend, &BytesN::from_array(&env, &[2; 32]), &limits),
+        Err(Ok(SdkError::from_contract_error(ERROR_CONTACT_EXISTS)))
+    );
+
+    // Until then the wallet's limits apply
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY - 1);
+    assert_eq!(
+        wallet.try_send(&friend, &token, &1001),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    wallet.send(&friend, &token, &1500);
+}
+
+#[test]
+fn contact_limit_replaces_the_wallet_limit() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let friend = Address::generate(&env);
+    let to = Address::generate(&env);
+    let mut limits = Map::new(&env);
+    limits.set(token.clone(), 2000);
+
+    wallet.add_contact(&friend, &BytesN::from_array(&env, &[1; 32]), &limits);
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    wallet.send(&friend, &token, &1500);
+    assert_eq!(
+        wallet.try_send(&friend, &token, &501),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+    wallet.send(&friend, &token, &500);
+
+    // Sends to the contact don't use up the wallet's own limit
+    wallet.send(&to, &token, &DAILY_LIMIT);
+}
+
+#[test]
+fn contact_without_limits_is_not_limited() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let friend = Address::generate(&env);
+
+    // An empty map doesn't mean a zero limit
+    wallet.add_contact(&friend, &BytesN::from_array(&env, &[1; 32]), &Map::new(&env));
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    wallet.send(&friend, &token, &5000);
+    wallet.send(&friend, &token, &5000);
+    assert_eq!(token::Client::new(&env, &token).balance(&friend), 10_000);
+}
+
+#[test]
+fn removed_contact_is_no_longer_trusted() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let friend = Address::generate(&env);
+
+    wallet.add_contact(&friend, &BytesN::from_array(&env, &[1; 32]), &Map::new(&env));
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    wallet.remove_contact(&friend);
+
+    assert_eq!(wallet.list_contacts(), Vec::new(&env));
+    assert_eq!(
+        wallet.try_get_contact(&friend),
+        Err(Ok(SdkError::from_contract_error(ERROR_CONTACT_NOT_FOUND)))
+    );
+    assert_eq!(
+        wallet.try_remove_contact(&friend),
+        Err(Ok(SdkError::from_contract_error(ERROR_CONTACT_NOT_FOUND)))
+    );
+    assert_eq!(
+        wallet.try_send(&friend, &token, &1001),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+}
+
+#[test]
+fn velocity_caps_payments_per_hour() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    wallet.set_velocity_rules(&VelocityRules {
+        max_per_hour: Some(2),
+        max_per_day: None,
+        new_recipient_period: 0,
+        new_recipient_limits: Map::new(&env),
+    });
+
+    wallet.send(&to, &token, &1);
+    wallet.send(&to, &token, &1);
+
+    assert_eq!(
+        wallet.try_send(&to, &token, &1),
+        Err(Ok(SdkError::from_contract_error(ERROR_HOURLY_TX_LIMIT)))
+    );
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_HOUR);
+    wallet.send(&to, &token, &1);
+}
+
+#[test]
+fn new_recipients_get_less() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let mut new_recipient_limits = Map::new(&env);
+    new_recipient_limits.set(token.clone(), 100);
+    wallet.set_velocity_rules(&VelocityRules {
+        max_per_hour: None,
+        max_per_day: None,
+        new_recipient_period: 48 * SECONDS_PER_HOUR,
+        new_recipient_limits,
+    });
+
+    wallet.send(&to, &token, &60);
+
+    assert_eq!(
+        wallet.try_send(&to, &token, &41),
+        Err(Ok(SdkError::from_contract_error(ERROR_NEW_RECIPIENT_LIMIT)))
+    );
+    assert_eq!(wallet.get_recipient(&to).unwrap().first_paid_at, NOW);
+
+    env.ledger().set_timestamp(NOW + 48 * SECONDS_PER_HOUR);
+    wallet.send(&to, &token, &500);
+}
+
+#[test]
+fn velocity_relaxation_waits_for_the_delay() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let strict = VelocityRules {
+        max_per_hour: Some(1),
+        max_per_day: None,
+        new_recipient_period: 0,
+        new_recipient_limits: Map::new(&env),
+    };
+    wallet.set_velocity_rules(&strict);
+    wallet.send(&to, &token, &1);
+
+    // Lifting the cap is queued and can be cancelled
+    wallet.set_velocity_rules(&VelocityRules { max_per_hour: None, ..strict.clone() });
+    assert_eq!(wallet.get_velocity_rules(), strict);
+    assert_eq!(
+        wallet.try_send(&to, &token, &1),
+        Err(Ok(SdkError::from_contract_error(ERROR_HOURLY_TX_LIMIT)))
+    );
+
+    wallet.cancel_velocity_rules();
+    assert_eq!(wallet.get_pending_velocity_rules(), None);
+
+    wallet.set_velocity_rules(&VelocityRules { max_per_hour: Some(5), ..strict.clone() });
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    assert_eq!(wallet.get_velocity_rules().max_per_hour, Some(5));
+    wallet.send(&to, &token, &1);
+    wallet.send(&to, &token, &1);
+}
+
+#[test]
+fn endless_new_recipient_period_does_not_overflow() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let mut new_recipient_limits = Map::new(&env);
+    new_recipient_limits.set(token.clone(), 100);
+    wallet.set_velocity_rules(&VelocityRules {
+        max_per_hour: None,
+        max_per_day: None,
+        new_recipient_period: u64::MAX,
+        new_recipient_limits,
+    });
+
+    wallet.send(&to, &token, &100);
+    assert_eq!(
+        wallet.try_send(&to, &token, &1),
+        Err(Ok(SdkError::from_contract_error(ERROR_NEW_RECIPIENT_LIMIT)))
+    );
+}
+
+#[test]
+fn large_send_waits_for_a_guardian() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let guardian = Address::generate(&env);
+    let token_client = token::Client::new(&env, &token);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 500);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: SECONDS_PER_DAY });
+    wallet.add_guardian(&guardian);
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    wallet.send(&to, &token, &700);
+    assert_eq!(token_client.balance(&to), 0);
+
+    let pending = wallet.list_pending();
+    assert_eq!(pending.len(), 1);
+    let id = pending.get(0).unwrap().id;
+
+    // The pending amount is held against the daily limit
+    assert_eq!(
+        wallet.try_send(&to, &token, &400),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+
+    wallet.approve_pending(&id, &StepUpApproval::Guardian(guardian));
+
+    assert_eq!(token_client.balance(&to), 700);
+    assert_eq!(wallet.list_pending().len(), 0);
+    assert_eq!(wallet.get_daily_spending(&token).spent, 700);
+}
+
+#[test]
+fn step_up_needs_another_passkey() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+    let mut laptop = Authenticator::new(&env, 2);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 500);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: SECONDS_PER_DAY });
+    wallet.add_passkey(&laptop.id, &laptop.public_key(&env));
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    // The phone signs the send, which records it as the initiator
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 700)];
+    let result = check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], contexts);
+    assert_eq!(result, Ok(()));
+    wallet.send(&to, &token, &700);
+
+    let id = wallet.list_pending().get(0).unwrap().id;
+    let payload = approval_payload(&env, &wallet.address, id);
+
+    assert_eq!(
+        wallet.try_approve_pending(&id, &StepUpApproval::Passkey(device.sign(&env, &payload))),
+        Err(Ok(SdkError::from_contract_error(ERROR_SELF_APPROVAL)))
+    );
+
+    wallet.approve_pending(&id, &StepUpApproval::Passkey(laptop.sign(&env, &payload)));
+    assert_eq!(token::Client::new(&env, &token).balance(&to), 700);
+}
+
+#[test]
+fn new_approvers_wait_for_the_delay() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup(&env);
+    let to = Address::generate(&env);
+    let guardian = Address::generate(&env);
+    let mut laptop = Authenticator::new(&env, 2);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 300);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: 2 * SECONDS_PER_DAY });
+
+    // A stolen signer enrolls its own approvers and sends right away
+    wallet.add_guardian(&guardian);
+    wallet.add_passkey(&laptop.id, &laptop.public_key(&env));
+
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 400)];
+    let result = check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], contexts);
+    assert_eq!(result, Ok(()));
+    wallet.send(&to, &token, &400);
+
+    // They stay too new for it even after the delay
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    let id = wallet.list_pending().get(0).unwrap().id;
+    let payload = approval_payload(&env, &wallet.address, id);
+
+    assert_eq!(
+        wallet.try_approve_pending(&id, &StepUpApproval::Guardian(guardian)),
+        Err(Ok(SdkError::from_contract_error(ERROR_APPROVER_TOO_NEW)))
+    );
+    assert_eq!(
+        wallet.try_approve_pending(&id, &StepUpApproval::Passkey(laptop.sign(&env, &payload))),
+        Err(Ok(SdkError::from_contract_error(ERROR_APPROVER_TOO_NEW)))
+    );
+
+    // The next pending transfer doesn't inherit the phone as its initiator
+    wallet.send(&to, &token, &400);
+    assert_eq!(wallet.list_pending().get(1).unwrap().initiators.len(), 0);
+}
+
+#[test]
+fn step_up_relaxation_waits_for_the_delay() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 100);
+    let policy = StepUpPolicy { thresholds, approval_window: SECONDS_PER_DAY };
+    wallet.set_step_up(&policy);
+
+    // Dropping the threshold is queued, transfers above it are still held
+    wallet.set_step_up(&StepUpPolicy { thresholds: Map::new(&env), ..policy.clone() });
+    assert_eq!(wallet.get_step_up(), policy);
+
+    wallet.send(&to, &token, &200);
+    assert_eq!(wallet.list_pending().len(), 1);
+
+    wallet.cancel_step_up();
+    assert_eq!(wallet.get_pending_step_up(), None);
+
+    wallet.set_step_up(&StepUpPolicy { thresholds: Map::new(&env), ..policy.clone() });
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+    assert_eq!(wallet.get_step_up().thresholds.len(), 0);
+
+    wallet.send(&to, &token, &200);
+    assert_eq!(token::Client::new(&env, &token).balance(&to), 200);
+}
+
+#[test]
+fn passkey_approval_is_checked_like_the_send() {
+    let env = Env::default();
+    let (wallet, _, token) = setup_with(&env, Some(env.register(MockUserManager, ())));
+    let to = Address::generate(&env);
+    let mut laptop = Authenticator::new(&env, 2);
+    let approver = Signer::Passkey(laptop.id.clone());
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 300);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: SECONDS_PER_DAY });
+    wallet.set_user_verification(&UserVerification::Never);
+    wallet.add_passkey(&laptop.id, &laptop.public_key(&env));
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    wallet.send(&to, &token, &400);
+    let payload = approval_payload(&env, &wallet.address, 0);
+
+    // User verification is required whatever the wallet's policy
+    let mut present_only = assertion(1);
+    present_only.flags = UP;
+    let signature = laptop.sign_with(&env, &payload, &present_only);
+    assert_eq!(
+        wallet.try_approve_pending(&0, &StepUpApproval::Passkey(signature)),
+        Err(Ok(SdkError::from_contract_error(ERROR_USER_VERIFICATION_REQUIRED)))
+    );
+
+    // The approver's amount cap applies to the held amount
+    let mut amount_caps = Map::new(&env);
+    amount_caps.set(token.clone(), 100);
+    let permissions = SignerPermissions {
+        contracts: None,
+        functions: Some(vec![&env, symbol_short!("send")]),
+        amount_caps,
+    };
+    wallet.set_permissions(&approver, &Some(permissions));
+    assert_eq!(
+        wallet.try_approve_pending(&0, &StepUpApproval::Passkey(laptop.sign(&env, &payload))),
+        Err(Ok(SdkError::from_contract_error(ERROR_PERMISSION_DENIED)))
+    );
+
+    // So do the attached policies
+    wallet.set_permissions(&approver, &None);
+    wallet.add_policy(&env.register(RejectAll, ()));
+    assert_eq!(
+        wallet.try_approve_pending(&0, &StepUpApproval::Passkey(laptop.sign(&env, &payload))),
+        Err(Ok(SdkError::from_contract_error(ERROR_POLICY_REJECTED)))
+    );
+}
+
+#[test]
+fn endless_approval_window_does_not_overflow() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 500);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: u64::MAX });
+    env.ledger().set_timestamp(NOW + SECONDS_PER_DAY);
+
+    wallet.send(&to, &token, &700);
+    assert_eq!(wallet.list_pending().get(0).unwrap().deadline, u64::MAX);
+}
+
+#[test]
+fn relayer_is_paid_the_fee_it_picked() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let relayer = Address::generate(&env);
+    let balances = token::Client::new(&env, &token);
+
+    assert_eq!(
+        wallet.try_send_with_fee(&to, &token, &300, &relayer, &51, &50),
+        Err(Ok(SdkError::from_contract_error(ERROR_FEE_TOO_HIGH)))
+    );
+
+    wallet.send_with_fee(&to, &token, &300, &relayer, &20, &50);
+    assert_eq!(balances.balance(&to), 300);
+    assert_eq!(balances.balance(&relayer), 20);
+    assert_eq!(balances.balance(&wallet.address), 10_000 - 320);
+}
+
+#[test]
+fn relayer_fee_counts_against_the_daily_limit() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let relayer = Address::generate(&env);
+
+    assert_eq!(
+        wallet.try_send_with_fee(&to, &token, &950, &relayer, &51, &100),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+
+    wallet.send_with_fee(&to, &token, &950, &relayer, &50, &100);
+    assert_eq!(wallet.get_daily_spending(&token).spent, DAILY_LIMIT);
+    assert_eq!(
+        wallet.try_send_with_fee(&to, &token, &1, &relayer, &0, &0),
+        Err(Ok(SdkError::from_contract_error(ERROR_DAILY_LIMIT_EXCEEDED)))
+    );
+}
+
+#[test]
+fn relayed_payment_is_signed_without_the_fee() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let relayer = Address::generate(&env);
+    let backend = ed25519_key(9);
+
+    wallet.add_signer(&ed25519_signer(&env, &backend));
+
+    // Signers approve the payment, the relayer and the most it may charge
+    let signed = (to.clone(), token.clone(), 300_i128, relayer.clone(), 50_i128);
+    let invoke = MockAuthInvoke {
+        contract: &wallet.address,
+        fn_name: "send_with_fee",
+        args: signed.into_val(&env),
+        sub_invokes: &[],
+    };
+    env.set_auths(&[ed25519_authorize(&env, &wallet.address, &backend, &invoke)]);
+
+    assert!(wallet.try_send_with_fee(&to, &token, &300, &relayer, &20, &60).is_err());
+    let other_relayer = Address::generate(&env);
+    assert!(wallet.try_send_with_fee(&to, &token, &300, &other_relayer, &20, &50).is_err());
+    assert!(wallet.try_send_with_fee(&to, &token, &400, &relayer, &20, &50).is_err());
+
+    // Any fee up to the signed maximum goes through
+    wallet.send_with_fee(&to, &token, &300, &relayer, &20, &50);
+    assert_eq!(token::Client::new(&env, &token).balance(&relayer), 20);
+}
+
+#[test]
+fn relayer_fee_counts_toward_the_step_up_threshold() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let relayer = Address::generate(&env);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 500);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: SECONDS_PER_DAY });
+
+    assert_eq!(
+        wallet.try_send_with_fee(&to, &token, &450, &relayer, &100, &100),
+        Err(Ok(SdkError::from_contract_error(ERROR_STEP_UP_REQUIRED)))
+    );
+    wallet.send_with_fee(&to, &token, &450, &relayer, &50, &100);
+}
+
+#[test]
+fn pending_transfer_expires_or_is_rejected() {
+    let env = Env::default();
+    let (wallet, _, token) = setup(&env);
+    let to = Address::generate(&env);
+    let guardian = Address::generate(&env);
+
+    let mut thresholds = Map::new(&env);
+    thresholds.set(token.clone(), 100);
+    wallet.set_step_up(&StepUpPolicy { thresholds, approval_window: SECONDS_PER_HOUR });
+    wallet.add_guardian(&guardian);
+
+    wallet.send(&to, &token, &200);
+    wallet.send(&to, &token, &300);
+
+    wallet.reject_pending(&0, &Some(guardian.clone()));
+    assert_eq!(wallet.list_pending().len(), 1);
+
+    env.ledger().set_timestamp(NOW + SECONDS_PER_HOUR + 1);
+    assert_eq!(
+        wallet.try_approve_pending(&1, &StepUpApproval::Guardian(guardian)),
+        Err(Ok(SdkError::from_contract_error(ERROR_PENDING_EXPIRED)))
+    );
+    assert_eq!(wallet.list_pending().len(), 0);
+}
+
+#[test]
+fn guardians_recover_the_wallet() {
+    let env = Env::default();
+    let (wallet, mut device, token) = setup_with(&env, Some(env.register(MockUserManager, ())));
+    let (alice, bob) = (Address::generate(&env), Address::generate(&env));
+    let mut new_device = Authenticator::new(&env, 3);
+    let stranger = Address::generate(&env);
+    let backend = ed25519_key(9);
+    let session = BytesN::from_array(&env, &ed25519_key(5).verifying_key().to_bytes());
+
+    // Left behind by whoever holds the lost device
+    wallet.add_signer(&ed25519_signer(&env, &backend));
+    wallet.set_threshold(&ThresholdPolicy { threshold: 2, functions: Map::new(&env) });
+    wallet.add_session_key(&session, &1000, &Map::new(&env), &vec![&env, symbol_short!("send")]);
+    wallet.add_policy(&env.register(RejectAll, ()));
+    wallet.add_contact(&stranger, &BytesN::from_array(&env, &[1; 32]), &Map::new(&env));
+
+    wallet.add_guardian(&alice);
+    wallet.add_guardian(&bob);
+    wallet.set_recovery_threshold(&2);
+
+    assert_eq!(
+        wallet.try_initiate_recovery(&stranger, &new_device.id, &new_device.public_key(&env)),
+        Err(Ok(SdkError::from_contract_error(ERROR_GUARDIAN_NOT_FOUND)))
+    );
+
+    wallet.initiate_recovery(&alice, &new_device.id, &new_device.public_key(&env));
+    assert_eq!(
+        wallet.try_complete_recovery(),
+        Err(Ok(SdkError::from_contract_error(ERROR_THRESHOLD_NOT_MET)))
+    );
+
+    wallet.approve_recovery(&bob);
+    assert_eq!(
+        wallet.try_complete_recovery(),
+        Err(Ok(SdkError::from_contract_error(ERROR_RECOVERY_PENDING)))
+    );
+
+    env.ledger().set_timestamp(NOW + RECOVERY_DELAY);
+    wallet.complete_recovery();
+
+    let passkeys = wallet.list_passkeys();
+    assert_eq!(passkeys.len(), 1);
+    assert_eq!(passkeys.get(0).unwrap().id, new_device.id);
+    assert_eq!(wallet.list_signers(), vec![&env, Signer::Passkey(new_device.id.clone())]);
+    assert_eq!(wallet.get_session_key(&session), None);
+    assert_eq!(wallet.list_policies().len(), 0);
+    assert_eq!(wallet.list_contacts().len(), 0);
+
+    // Guardians stay as they were
+    assert_eq!(wallet.list_guardians(), vec![&env, alice.clone(), bob.clone()]);
+
+    // The new device alone is enough again
+    let to = Address::generate(&env);
+    let payload = [2; 32];
+    let signature = SignerSignature::Passkey(new_device.sign(&env, &payload));
+    let contexts = vec![&env, send_call(&env, &wallet.address, &to, &token, 10)];
+    let result = check_auth(&env, &wallet.address, &payload, vec![&env, signature], contexts);
+    assert_eq!(result, Ok(()));
+
+    // The lost device no longer signs for the wallet
+    let signature = SignerSignature::Passkey(device.sign(&env, &[1; 32]));
+    assert_eq!(
+        check_auth(&env, &wallet.address, &[1; 32], vec![&env, signature], vec![&env]),
+        contract_error(ERROR_PASSKEY_NOT_FOUND)
+    );
+}
+
+#[test]
+fn recovery_needs_guardians() {
+    let env = Env::default();
+    let (wallet, _, _) = setup(&env);
+    let new_device = Authenticator::new(&env, 3);
+    let stranger = Address::generate(&env);
+
+    assert_eq!(
+        wallet.try_initiate_recovery(&stranger, &new_device.id, &new_device.public_key(&env)),
+        Err(Ok(SdkError::from_contract_error(ERROR_UNAUTHORIZED)))
+    );
+}
+
+#[test]
+fn owner_can_cancel_recovery() {
+    let env = Env::default();
+    let (wallet, _, _) = setup(&env);
+    let guardian = Address::generate(&env);
+    let new_device = Authenticator::new(&env, 3);
+
+    wallet.add_guardian(&guardian);
+    wallet.set_recovery_threshold(&1);
+    wallet.initiate_recovery(&guardian, &new_device.id, &new_device.public_key(&env));
+    wallet.cancel_recovery();
+
+    env.ledger().set_timestamp(NOW + RECOVERY_DELAY);
+    assert_eq!(
+        wallet.try_complete_recovery(),
+        Err(Ok(SdkError::from_contract_error(ERROR_NO_RECOVERY_PENDING)))
+    );
 }